@@ -1,13 +1,46 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+
 pub trait Cache<K, V> {
     /// Create a new cache with a maximum size.
     fn with_capacity(max_size: usize) -> Self;
 
-    /// Get a reference to the value associated with the given key.
-    fn get(&mut self, key: &K) -> Option<&V>;
+    /// Get a reference to the value associated with the given key, or any borrowed form of it.
+    ///
+    /// Unlike `HashMap::get`, the borrowed form must be convertible back to an owned key:
+    /// a read-through cache needs one to load and store a missing value. The conversion
+    /// only happens on a miss when the cache has a loader.
+    fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized;
+
+    /// Add the given key-value to the cache, returning the previous value of the key if any.
+    fn put(&mut self, key: K, value: V) -> Option<V>;
+
+    /// Remove the given key, or any borrowed form of it, from the cache.
+    fn invalidate<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized;
+
+    /// Get the number of keys currently stored in the cache.
+    fn len(&self) -> usize;
+
+    /// Check if the cache contains no key.
+    fn is_empty(&self) -> bool;
+
+    /// Get the maximum number of keys the cache can hold.
+    fn capacity(&self) -> usize;
 
-    /// Add the given key-value to the cache.
-    fn put(&mut self, key: K, value: V);
+    /// Get the eviction policy implemented by the cache.
+    fn policy(&self) -> CachePolicy;
+}
 
-    /// Remove the given key from the cache.
-    fn invalidate(&mut self, key: &K);
+/// Eviction policy of a cache, deciding which entry is removed when it is full.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// The least recently used entry is removed.
+    Lru,
 }
\ No newline at end of file
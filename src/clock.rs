@@ -0,0 +1,38 @@
+use std::time::Instant;
+
+/// # Clock
+///
+/// Source of the current time used by the cache for expirations and access times.
+/// Replacing it makes the time based behaviors testable without waiting for real time to pass.
+///
+/// **Exemple**:
+/// ```
+///use std::sync::Arc;
+///use std::time::Instant;
+///use lru_cache::clock::Clock;
+///use lru_cache::lru_cache::LRUCache;
+///
+/// struct FrozenClock(Instant);
+///
+/// impl Clock for FrozenClock {
+///     fn now(&self) -> Instant {
+///         self.0
+///     }
+/// }
+///
+/// let cache: LRUCache<i32, &str> = LRUCache::with_capacity(3).with_clock(Arc::new(FrozenClock(Instant::now())));
+/// ```
+pub trait Clock {
+    /// Get the current time.
+    fn now(&self) -> Instant;
+}
+
+/// Clock reading the system monotonic time, used by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -1,2 +1,9 @@
 pub mod cache;
-pub mod lru_cache;
\ No newline at end of file
+pub mod clock;
+mod fnv;
+pub mod lru_cache;
+#[cfg(feature = "oplog")]
+pub mod oplog;
+mod rng;
+pub mod stats;
+pub mod sync_cache;
\ No newline at end of file
@@ -1,18 +1,56 @@
-use std::collections::HashMap;
-use std::hash::Hash;
-use crate::cache::Cache;
+use std::borrow::Borrow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::iter;
+use std::mem;
+use std::num::NonZeroUsize;
+use std::ops::Add;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::cache::{Cache, CachePolicy};
+use crate::clock::{Clock, SystemClock};
+use crate::fnv::FnvHasher;
+use crate::rng::XorShift;
+#[cfg(feature = "oplog")]
+use crate::oplog::Op;
+use crate::stats::{CacheStats, Counters};
+#[cfg(feature = "selftest")]
+use crate::stats::BenchResult;
 
 /// # LRU Cache - Least Recently Used
 ///
 /// **entries** contains the cache entries.
-/// **map** contains indexes of entries in the entries vector.
+/// **map** contains indexes of entries in the entries vector, hashed with `S`.
+/// **free** contains indexes of vacant slots in the entries vector, reused by new entries.
 /// **first** and last are indexes of the first and last entries.
 /// **max_size** is the maximum number of entries in the cache.
+/// **shrink_pending** is set while the cache holds more entries than `max_size` after a shrink.
+/// **loader** is an optional function called to fill the cache on a miss.
+/// **weigher** is an optional function giving the weight of an entry for the weighted hit ratio.
+/// **normalizer** is an optional function applied to every value before it is stored.
+/// **on_evict** is an optional function receiving the entries evicted to make room.
+/// **on_first_full** is an optional function called once when the cache becomes full.
+/// **counters** tracks hits, misses, evictions and invalidations.
+/// **lazy_promotion** defers the move to the front of accessed entries until the next `put`.
+/// **pending_promotions** is the number of entries accessed since the last promotion flush.
+/// **next_insertion_seq** is the sequence number given to the next inserted key.
+/// **next_generation** is the generation given to the next slot reserved with `reserve_slot`.
+/// **promotion_threshold** is the number of accesses before an entry is moved to the front, 0 for plain LRU.
+/// **clock** gives the current time for expirations and access times.
+/// **rng** is used by probabilistic operations such as early expiration.
+/// **op_log** records the operations performed, with the `oplog` feature.
 ///
 /// **How it works**:
 /// - When a key is added to the cache, it is moved to the front.
 /// - When a key is accessed, it is moved to the front.
-/// - When the cache is full, the last entry is removed.
+/// - With a promotion threshold, new keys are added at the back and only moved to the front
+///   once they have been accessed enough times.
+/// - When the cache is full, the last entry that is not pinned is removed.
+/// - When an entry added with a time-to-live expires, it is removed on its next access.
 ///
 /// **Exemple**:
 /// ```
@@ -28,207 +66,4732 @@ use crate::cache::Cache;
 /// assert_eq!(cache.get(&2), Some(&"B"));
 /// assert_eq!(cache.get(&3), Some(&"C"));
 ///
-pub struct LRUCache<K, V> {
+pub struct LRUCache<K, V, S = RandomState> {
     entries: Vec<Entry<K, V>>,
-    map: HashMap<K, usize>, // Clé -> index
+    map: HashMap<K, usize, S>, // Clé -> index
+    free: Vec<usize>,
     first: Option<usize>,
     last: Option<usize>,
     max_size: usize,
+    shrink_pending: bool,
+    loader: Option<Box<Loader<K, V>>>,
+    weigher: Option<Box<Weigher<K, V>>>,
+    normalizer: Option<Box<Normalizer<V>>>,
+    on_evict: Option<Box<EvictionListener<K, V>>>,
+    on_first_full: Option<Box<dyn FnOnce() + Send + Sync>>,
+    counters: Counters,
+    lazy_promotion: bool,
+    pending_promotions: usize,
+    next_insertion_seq: u64,
+    next_generation: u64,
+    promotion_threshold: u64,
+    clock: Arc<dyn Clock + Send + Sync>,
+    rng: XorShift,
+    #[cfg(feature = "oplog")]
+    op_log: Vec<Op<K>>,
 }
 
+/// Maximum size of a cache created with `Default::default`.
+pub const DEFAULT_CAPACITY: usize = 16;
+
+/// Function used by a read-through cache to load a missing value.
+type Loader<K, V> = dyn FnMut(&K) -> Option<V> + Send + Sync;
+
+/// Function giving the weight of an entry, such as the cost of loading it again.
+type Weigher<K, V> = dyn Fn(&K, &V) -> u64 + Send + Sync;
+
+/// Function receiving the entries evicted from the cache.
+type EvictionListener<K, V> = dyn FnMut(K, V) + Send + Sync;
+
+/// Function giving the canonical form of a value before it is stored.
+type Normalizer<V> = dyn Fn(V) -> V + Send + Sync;
+
+/// Index of an added entry with the entries evicted to make room for it,
+/// or the rejected key and value.
+type InsertResult<K, V> = Result<(usize, Vec<(K, V)>), (K, Option<V>)>;
+
 ///
 /// Cache entry
 ///
 /// Contains key-value and next and previous entry indexes.
 ///
+#[derive(Clone)]
 struct Entry<K, V> {
     key: K,
     value: Option<V>,
     prev: Option<usize>,
     next: Option<usize>,
+    pinned: bool,
+    touched: bool,
+    expires_at: Option<Instant>,
+    tag: Option<u64>,
+    last_access: Instant,
+    access_count: u64,
+    inserted_at: Instant,
+    insertion_seq: u64,
+    generation: u64,
+}
+
+impl<K, V> Entry<K, V> {
+    /// Get the value of the entry, unless it is a reserved slot or it has expired at `now`.
+    fn live_value(&self, now: Instant) -> Option<&V> {
+        self.value.as_ref().filter(|_| self.expires_at.is_none_or(|expires_at| expires_at > now))
+    }
+}
+
+///
+/// Handle over an entry of the cache, returned by `LRUCache::get_entry`.
+///
+/// Keeps the entry index so that reading, modifying, promoting or removing it
+/// does not require another lookup of the key.
+///
+pub struct OccupiedEntry<'a, K, V, S = RandomState> {
+    cache: &'a mut LRUCache<K, V, S>,
+    index: usize,
 }
 
-impl<K, V> Cache<K, V> for LRUCache<K, V>
+impl<K, V, S> OccupiedEntry<'_, K, V, S>
 where
     K: Hash + Eq + Clone,
+    S: BuildHasher + Default,
 {
-    fn with_capacity(max_size: usize) -> Self {
-        Self {
-            entries: Vec::with_capacity(max_size),
-            map: HashMap::with_capacity(max_size),
-            first: None,
-            last: None,
-            max_size,
-        }
+    /// Get a reference to the key of the entry.
+    pub fn key(&self) -> &K {
+        &self.cache.entries[self.index].key
     }
 
-    fn get(&mut self, key: &K) -> Option<&V> {
-        if self.map.get(key).is_some() {
-            let index = *self.map.get(key).unwrap();
+    /// Get a reference to the value of the entry.
+    pub fn get(&self) -> &V {
+        self.cache.entries[self.index].value.as_ref().expect("occupied entry has a value")
+    }
 
-            self._move_to_front(index);
+    /// Get a mutable reference to the value of the entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        self.cache.entries[self.index].value.as_mut().expect("occupied entry has a value")
+    }
 
-            self.entries[index].value.as_ref()
-        } else {
-            None
-        }
+    /// Record an access to the entry, moving it to the front like `LRUCache::get` does.
+    pub fn promote(&mut self) {
+        self.cache._promote(self.index);
     }
 
-    fn put(&mut self, key: K, value: V) {
-        if self.map.get(&key).is_some() {
-            let index = *self.map.get(&key).unwrap();
-            self.entries[index].value = Some(value);
-            self._move_to_front(index);
-            return;
-        }
+    /// Remove the entry from the cache and return its value.
+    pub fn remove(self) -> V {
+        let (_, value) = self.cache._remove(self.index);
+        value.expect("occupied entry has a value")
+    }
+}
 
-        let new_entry = Entry {
-            key: key.clone(),
-            value: Some(value),
-            prev: None,
-            next: self.first,
-        };
-        let new_index = self.entries.len();
+///
+/// Iterator over the entries of the cache, from the most to the least recently used,
+/// returned by `LRUCache::iter`.
+///
+/// Its length is the number of live entries: a slot reserved with `reserve_slot`
+/// and not filled yet, or an expired entry, is neither counted nor yielded.
+///
+pub struct Iter<'a, K, V> {
+    entries: &'a [Entry<K, V>],
+    next: Option<usize>,
+    remaining: usize,
+    now: Instant,
+}
 
-        if self.entries.len() >= self.max_size {
-            self._remove_last();
-        }
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
 
-        self.entries.push(new_entry);
-        self.map.insert(key, new_index);
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        while let Some(index) = self.next {
+            let entry = &self.entries[index];
+            self.next = entry.next;
 
-        match self.first {
-            None => {
-                self.first = Some(new_index);
-                self.last = Some(new_index);
-            }
-            Some(old_first) => {
-                self.first = Some(new_index);
-                self.entries[new_index].next = Some(old_first);
-                self.entries[old_first].prev = Some(new_index);
+            if let Some(value) = entry.live_value(self.now) {
+                self.remaining -= 1;
+                return Some((&entry.key, value));
             }
         }
+
+        None
     }
 
-    fn invalidate(&mut self, key: &K) {
-        if self.map.get(key).is_some() {
-            let index = *self.map.get(key).unwrap();
-            let prev = self.entries[index].prev;
-            let next = self.entries[index].next;
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
 
-            if Some(index) == self.first {
-                self.first = next;
-            }
+impl<K, V> ExactSizeIterator for Iter<'_, K, V> {}
 
-            if Some(index) == self.last {
-                self.last = prev;
-            }
+///
+/// Iterator consuming the cache, yielding its entries from the least to the most
+/// recently used, returned by `LRUCache::into_iter`. Expired entries are skipped.
+///
+pub struct IntoIter<K, V> {
+    entries: Vec<Option<Entry<K, V>>>,
+    next: Option<usize>,
+    now: Instant,
+}
 
-            if prev.is_some() {
-                self.entries[prev.unwrap()].next = next;
-            }
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
 
-            if next.is_some() {
-                self.entries[next.unwrap()].prev = prev;
-            }
+    fn next(&mut self) -> Option<(K, V)> {
+        while let Some(index) = self.next {
+            let entry = self.entries[index].take().expect("entry linked twice");
+            self.next = entry.prev;
 
-            self.map.remove(key);
-            self.entries[index].value = None;
+            if entry.live_value(self.now).is_some() {
+                return entry.value.map(|value| (entry.key, value));
+            }
         }
+
+        None
+    }
+}
+
+impl<K, V, S> IntoIterator for LRUCache<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    /// Consume the cache, yielding its entries from the least to the most recently used.
+    fn into_iter(self) -> IntoIter<K, V> {
+        let now = self.clock.now();
+        IntoIter { entries: self.entries.into_iter().map(Some).collect(), next: self.last, now }
     }
 }
 
-impl <K, V> LRUCache<K, V>
+///
+/// Iterator removing and yielding the entries matching a predicate, returned by
+/// `LRUCache::drain_filter`.
+///
+/// Entries are visited from the most to the least recently used, and only removed
+/// once yielded: dropping the iterator early keeps the entries not visited yet.
+///
+pub struct DrainFilter<'a, K, V, F, S = RandomState>
+where
+    F: FnMut(&K, &V) -> bool,
+{
+    cache: &'a mut LRUCache<K, V, S>,
+    next: Option<usize>,
+    pred: F,
+}
+
+impl<K, V, F, S> Iterator for DrainFilter<'_, K, V, F, S>
 where
     K: Hash + Eq + Clone,
+    F: FnMut(&K, &V) -> bool,
+    S: BuildHasher + Default,
 {
-    fn _remove_last(&mut self) {
-        if self.last.is_some() {
-            let last_index = self.last.unwrap();
+    type Item = (K, V);
 
-            let last_key = &self.entries[last_index].key;
-            self.map.remove(last_key);
+    fn next(&mut self) -> Option<(K, V)> {
+        while let Some(index) = self.next {
+            let entry = &self.cache.entries[index];
+            self.next = entry.next;
 
-            self.last = self.entries[last_index].prev;
-            if self.last.is_some() {
-                let new_last = self.last.unwrap();
-                self.entries[new_last].next = None;
-            } else {
-                self.first = None;
+            if entry.value.as_ref().is_some_and(|value| (self.pred)(&entry.key, value)) {
+                let (key, value) = self.cache._remove(index);
+                return value.map(|value| (key, value));
             }
         }
+
+        None
     }
+}
 
-    fn _move_to_front(&mut self, index: usize) {
-        if Some(index) == self.first {
-            return;
+///
+/// Iterator removing and yielding every entry of the cache, from the least to the most
+/// recently used, returned by `LRUCache::drain`.
+///
+/// The cache is left empty when the iterator is dropped, even if it was not fully consumed.
+///
+pub struct Drain<'a, K, V, S = RandomState>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Default,
+{
+    cache: &'a mut LRUCache<K, V, S>,
+}
+
+impl<K, V, S> Iterator for Drain<'_, K, V, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Default,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        while let Some(index) = self.cache.last {
+            if let (key, Some(value)) = self.cache._remove(index) {
+                return Some((key, value));
+            }
         }
 
-        let prev = self.entries[index].prev;
-        let next = self.entries[index].next;
+        None
+    }
+}
+
+impl<K, V, S> Drop for Drain<'_, K, V, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Default,
+{
+    fn drop(&mut self) {
+        self.cache.clear();
+    }
+}
+
+///
+/// State of a key in the cache, returned by `LRUCache::peek_state`.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryState {
+    Absent,
+    Live,
+    Expired,
+}
+
+///
+/// Entry of the cache with its metadata, returned by `LRUCache::entries_with_metadata`.
+///
+/// **rank** is the position in the recency order, 0 for the most recently used entry.
+/// **ttl_remaining** is the time left before the entry expires, if it has a time-to-live.
+///
+#[derive(Debug)]
+pub struct EntryInfo<'a, K, V> {
+    pub key: &'a K,
+    pub value: &'a V,
+    pub rank: usize,
+    pub access_count: u64,
+    pub inserted_at: Instant,
+    pub ttl_remaining: Option<Duration>,
+    pub tag: Option<u64>,
+    pub pinned: bool,
+}
+
+///
+/// Token for a slot reserved by `LRUCache::reserve_slot`, to be given back to `fill_slot`.
+///
+#[derive(Debug)]
+pub struct SlotToken<K> {
+    key: K,
+    generation: Option<u64>,
+}
+
+impl<K, V, S> Cache<K, V> for LRUCache<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Default,
+{
+    fn with_capacity(max_size: usize) -> Self {
+        Self::with_hasher(max_size, S::default())
+    }
+
+    fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(index) = self._lookup_live(key) {
+            self.counters.record_hit();
+            let entry = &self.entries[index];
+            let weight = entry.value.as_ref().map_or(1, |value| self._weight(&entry.key, value));
+            self.counters.weighted_hits += weight;
+            self._promote(index);
 
-        if prev.is_some() {
-            self.entries[prev.unwrap()].next = next;
+            self.entries[index].value.as_ref()
+        } else {
+            self.counters.record_miss();
+            // The key is only converted to an owned key when there is a loader to call.
+            let Some(loader) = self.loader.as_mut() else {
+                self.counters.weighted_misses += 1;
+                return None;
+            };
+            let key = key.to_owned();
+            let Some(value) = loader(&key) else {
+                self.counters.weighted_misses += 1;
+                return None;
+            };
+            self.counters.weighted_misses += self._weight(&key, &value);
+            self.put(key.clone(), value);
+
+            let index = *self.map.get::<K>(&key)?;
+            self.entries[index].value.as_ref()
         }
+    }
+
+    fn put(&mut self, key: K, value: V) -> Option<V> {
+        self.try_put(key, value).ok().flatten()
+    }
+
+    fn invalidate<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.pop(key);
+    }
+
+    /// Expired entries count until they are removed on their next access,
+    /// and so do the slots reserved with `reserve_slot`.
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    fn capacity(&self) -> usize {
+        self.max_size
+    }
+
+    fn policy(&self) -> CachePolicy {
+        CachePolicy::Lru
+    }
+}
+
+impl<K, V, S> Default for LRUCache<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Default,
+{
+    /// Create an empty cache with a maximum size of `DEFAULT_CAPACITY`.
+    fn default() -> Self {
+        Self::with_hasher(DEFAULT_CAPACITY, S::default())
+    }
+}
 
-        if next.is_some() {
-            self.entries[next.unwrap()].prev = prev;
+impl<K, V, S> Clone for LRUCache<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    /// Copy the cache with its entries, their exact recency order and its statistics.
+    /// The loader, weigher, normalizer, `on_evict` and `on_first_full` functions cannot be
+    /// copied and are not carried over.
+    fn clone(&self) -> Self {
+        LRUCache {
+            entries: self.entries.clone(),
+            map: self.map.clone(),
+            free: self.free.clone(),
+            first: self.first,
+            last: self.last,
+            max_size: self.max_size,
+            shrink_pending: self.shrink_pending,
+            loader: None,
+            weigher: None,
+            normalizer: None,
+            on_evict: None,
+            on_first_full: None,
+            counters: self.counters.clone(),
+            lazy_promotion: self.lazy_promotion,
+            pending_promotions: self.pending_promotions,
+            next_insertion_seq: self.next_insertion_seq,
+            next_generation: self.next_generation,
+            promotion_threshold: self.promotion_threshold,
+            clock: self.clock.clone(),
+            rng: self.rng.clone(),
+            #[cfg(feature = "oplog")]
+            op_log: self.op_log.clone(),
         }
+    }
+}
 
-        if Some(index) == self.last {
-            self.last = prev;
+impl<K, V, S> fmt::Debug for LRUCache<K, V, S>
+where
+    K: fmt::Debug,
+    V: fmt::Debug,
+{
+    /// Show the capacity and the live entries, from the most to the least recently used.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LRUCache")
+            .field("capacity", &self.max_size)
+            .field("entries", &DebugEntries(self))
+            .finish()
+    }
+}
+
+/// Entries of a cache formatted as a map in recency order, for the `Debug` implementation.
+struct DebugEntries<'a, K, V, S>(&'a LRUCache<K, V, S>);
+
+impl<K: fmt::Debug, V: fmt::Debug, S> fmt::Debug for DebugEntries<'_, K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cache = self.0;
+        let now = cache.clock.now();
+        let mut entries = f.debug_map();
+        let mut index = cache.first;
+        while let Some(current) = index {
+            let entry = &cache.entries[current];
+            if let Some(value) = entry.live_value(now) {
+                entries.entry(&entry.key, value);
+            }
+            index = entry.next;
         }
+        entries.finish()
+    }
+}
 
-        if self.first.is_some() {
-            let old_first = self.first.unwrap();
-            self.entries[old_first].prev = Some(index);
+impl<K, V> LRUCache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Create a new cache with a maximum size, using the default hasher.
+    pub fn with_capacity(max_size: usize) -> Self {
+        Self::with_hasher(max_size, RandomState::new())
+    }
+
+    /// Create a cache sized from a memory budget in bytes, at least one entry.
+    /// Only the size of an entry itself is counted: memory allocated on the heap by
+    /// the keys or values (such as the content of a `String`) is ignored.
+    pub fn with_memory_budget(bytes: usize) -> Self {
+        Self::with_capacity((bytes / mem::size_of::<Entry<K, V>>()).max(1))
+    }
+
+    /// Create a read-through cache: on a `get` miss, `loader` is called with the key
+    /// and the value it returns, if any, is added to the cache.
+    pub fn with_loader<F>(max_size: usize, loader: F) -> Self
+    where
+        F: FnMut(&K) -> Option<V> + Send + Sync + 'static,
+    {
+        let mut cache = Self::with_capacity(max_size);
+        cache.loader = Some(Box::new(loader));
+        cache
+    }
+
+    /// Create a cache computing its weighted hit ratio with `weigher`, so that lookups
+    /// of heavier entries count more. See `weighted_hit_ratio`.
+    pub fn with_weigher<F>(max_size: usize, weigher: F) -> Self
+    where
+        F: Fn(&K, &V) -> u64 + Send + Sync + 'static,
+    {
+        let mut cache = Self::with_capacity(max_size);
+        cache.weigher = Some(Box::new(weigher));
+        cache
+    }
+
+    /// Create a cache passing every added value through `normalize` before storing it,
+    /// for instance to trim strings or clamp numbers.
+    pub fn with_value_normalizer<F>(max_size: usize, normalize: F) -> Self
+    where
+        F: Fn(V) -> V + Send + Sync + 'static,
+    {
+        let mut cache = Self::with_capacity(max_size);
+        cache.normalizer = Some(Box::new(normalize));
+        cache
+    }
+
+    /// Create a cache where `get` only marks entries as accessed instead of moving them
+    /// to the front. Marked entries are moved to the front, keeping their relative order,
+    /// on the next `put` or `flush_promotions`, which avoids relinking the list on every read.
+    pub fn with_lazy_promotion(max_size: usize) -> Self {
+        let mut cache = Self::with_capacity(max_size);
+        cache.lazy_promotion = true;
+        cache
+    }
+
+    /// Create a scan resistant cache: new keys are added at the back and an entry is only
+    /// moved to the front once it has been accessed `threshold` times, so that keys read
+    /// only once cannot evict the frequently used ones.
+    pub fn with_promotion_threshold(max_size: usize, threshold: u64) -> Self {
+        let mut cache = Self::with_capacity(max_size);
+        cache.promotion_threshold = threshold;
+        cache
+    }
+}
+
+impl <K, V, S> LRUCache<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher,
+{
+    /// Create a new cache with a maximum size, hashing its keys with `hasher`, for instance
+    /// a faster hasher for trusted keys or a DoS resistant one for untrusted keys.
+    pub fn with_hasher(max_size: usize, hasher: S) -> Self {
+        Self {
+            entries: Vec::with_capacity(max_size),
+            map: HashMap::with_capacity_and_hasher(max_size, hasher),
+            free: Vec::new(),
+            first: None,
+            last: None,
+            max_size,
+            shrink_pending: false,
+            loader: None,
+            weigher: None,
+            normalizer: None,
+            on_evict: None,
+            on_first_full: None,
+            counters: Counters::default(),
+            lazy_promotion: false,
+            pending_promotions: 0,
+            next_insertion_seq: 0,
+            next_generation: 0,
+            promotion_threshold: 0,
+            clock: Arc::new(SystemClock),
+            rng: XorShift::from_entropy(),
+            #[cfg(feature = "oplog")]
+            op_log: Vec::new(),
         }
+    }
 
-        self.entries[index].prev = None;
-        self.entries[index].next = self.first;
-        self.first = Some(index);
+    /// Rebuild the cache with another hasher, keeping its entries and their recency order.
+    pub fn with_rehashed<S2: BuildHasher>(self, hasher: S2) -> LRUCache<K, V, S2> {
+        let mut map = HashMap::with_capacity_and_hasher(self.map.capacity(), hasher);
+        map.extend(self.map);
+
+        LRUCache {
+            entries: self.entries,
+            map,
+            free: self.free,
+            first: self.first,
+            last: self.last,
+            max_size: self.max_size,
+            shrink_pending: self.shrink_pending,
+            loader: self.loader,
+            weigher: self.weigher,
+            normalizer: self.normalizer,
+            on_evict: self.on_evict,
+            on_first_full: self.on_first_full,
+            counters: self.counters,
+            lazy_promotion: self.lazy_promotion,
+            pending_promotions: self.pending_promotions,
+            next_insertion_seq: self.next_insertion_seq,
+            next_generation: self.next_generation,
+            promotion_threshold: self.promotion_threshold,
+            clock: self.clock,
+            rng: self.rng,
+            #[cfg(feature = "oplog")]
+            op_log: self.op_log,
+        }
     }
 }
 
+impl <K, V, S> LRUCache<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Default,
+{
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Move the entries accessed since the last flush to the front of the cache.
+    pub fn flush_promotions(&mut self) {
+        let mut index = self.last;
+        while self.pending_promotions > 0 {
+            let Some(current) = index else { break };
+            index = self.entries[current].prev;
 
-    #[test]
-    fn test_get_put() {
-        let mut cache = LRUCache::with_capacity(3);
-        cache.put("A", String::from("A"));
-        cache.put("B", String::from("B"));
-        cache.put("C", String::from("C"));
+            if self.entries[current].touched {
+                self.pending_promotions -= 1;
+                self._move_to_front(current);
+            }
+        }
 
-        assert_eq!(cache.get(&"A"), Some(&String::from("A")));
-        assert_eq!(cache.get(&"B"), Some(&String::from("B")));
-        assert_eq!(cache.get(&"C"), Some(&String::from("C")));
+        self.pending_promotions = 0;
     }
 
-    #[test]
-    fn test_capacity() {
-        let mut cache = LRUCache::with_capacity(3);
-        cache.put("A", String::from("A"));
-        cache.put("B", String::from("B"));
-        cache.put("C", String::from("C"));
-        cache.put("D", String::from("D"));
+    /// Register a function receiving the key and value of every entry evicted by the LRU policy,
+    /// for instance to write it back to a store. Invalidated, expired or drained entries are
+    /// not passed to it. The function takes the evicted entries: once registered, `push`,
+    /// `evict_to` and the other methods returning evicted entries no longer return them.
+    pub fn on_evict<F: FnMut(K, V) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_evict = Some(Box::new(f));
+        self
+    }
 
-        assert_eq!(cache.get(&"A"), None);
-        assert_eq!(cache.get(&"B"), Some(&String::from("B")));
-        assert_eq!(cache.get(&"C"), Some(&String::from("C")));
-        assert_eq!(cache.get(&"D"), Some(&String::from("D")));
+    /// Read the current time from `clock` instead of the system clock, for expirations
+    /// and access times. It can be combined with any constructor.
+    pub fn with_clock<C: Clock + Send + Sync + 'static>(mut self, clock: Arc<C>) -> Self {
+        self.clock = clock;
+        self
     }
 
-    #[test]
-    fn test_invalidate() {
-        let mut cache = LRUCache::with_capacity(3);
-        cache.put("A", String::from("A"));
-        cache.put("B", String::from("B"));
+    /// Register a function called once, by the next insertion of a key that leaves the cache full.
+    /// It is not called again afterwards, even after a `clear`, unless registered again.
+    pub fn on_first_full<F: FnOnce() + Send + Sync + 'static>(&mut self, f: F) {
+        self.on_first_full = Some(Box::new(f));
+    }
 
-        cache.invalidate(&"A");
-        assert_eq!(cache.get(&"A"), None);
-        assert_eq!(cache.get(&"B"), Some(&String::from("B")));
+    /// Get a mutable reference to the value of the given key, moving it to the front.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some(index) = self._lookup_live(key) else {
+            self.counters.record_miss();
+            return None;
+        };
+
+        self.counters.record_hit();
+        self._promote(index);
+        self.entries[index].value.as_mut()
+    }
+
+    /// Get the value of the given key with the time elapsed since its previous access,
+    /// moving it to the front.
+    pub fn get_with_idle(&mut self, key: &K) -> Option<(&V, Duration)> {
+        let Some(index) = self._lookup_live(key) else {
+            self.counters.record_miss();
+            return None;
+        };
+
+        let idle = self.clock.now().saturating_duration_since(self.entries[index].last_access);
+        self.counters.record_hit();
+        self._promote(index);
+        self.entries[index].value.as_ref().map(|value| (value, idle))
+    }
+
+    /// Get the value of the given key only if it satisfies `pred`.
+    /// A rejected value is treated as a miss and the entry is not moved to the front.
+    pub fn get_if<F: Fn(&V) -> bool>(&mut self, key: &K, pred: F) -> Option<&V> {
+        let index = match self._lookup_live(key) {
+            Some(index) if self.entries[index].value.as_ref().is_some_and(&pred) => index,
+            _ => {
+                self.counters.record_miss();
+                return None;
+            }
+        };
+
+        self.counters.record_hit();
+        self._promote(index);
+        self.entries[index].value.as_ref()
+    }
+
+    /// Add the given entries, storing each key once with its last value. The keys are added in
+    /// the order of their last occurrence, as a sequence of `put` would leave them, but earlier
+    /// duplicates do not cause extra promotions or evictions.
+    pub fn put_dedup<I: IntoIterator<Item = (K, V)>>(&mut self, items: I) {
+        let items: Vec<(K, V)> = items.into_iter().collect();
+        let mut last_positions = HashMap::with_capacity(items.len());
+        for (position, (key, _)) in items.iter().enumerate() {
+            last_positions.insert(key.clone(), position);
+        }
+
+        for (position, (key, value)) in items.into_iter().enumerate() {
+            if last_positions[&key] == position {
+                self.put(key, value);
+            }
+        }
     }
-}
\ No newline at end of file
+
+    /// Compute entries from `inputs` with `produce` on several threads, then add them to the cache.
+    /// Only the production of the entries is parallel: they are added one by one in the order
+    /// of `inputs`, so the resulting recency order is deterministic.
+    pub fn par_extend<T, F>(&mut self, inputs: Vec<T>, produce: F)
+    where
+        T: Send,
+        K: Send,
+        V: Send,
+        F: Fn(T) -> (K, V) + Sync,
+    {
+        let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let chunk_size = inputs.len().div_ceil(threads).max(1);
+
+        let mut chunks = Vec::with_capacity(threads);
+        let mut inputs = inputs.into_iter().peekable();
+        while inputs.peek().is_some() {
+            chunks.push(inputs.by_ref().take(chunk_size).collect::<Vec<T>>());
+        }
+
+        let produce = &produce;
+        thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| scope.spawn(move || chunk.into_iter().map(produce).collect::<Vec<_>>()))
+                .collect();
+
+            for handle in handles {
+                for (key, value) in handle.join().expect("producer thread panicked") {
+                    self.put(key, value);
+                }
+            }
+        });
+    }
+
+    /// Get the value of the given key, or `default` on a miss. The default value is not inserted.
+    pub fn get_or<'a>(&'a mut self, key: &K, default: &'a V) -> &'a V {
+        self.get(key).unwrap_or(default)
+    }
+
+    /// Get the value of the given key, moving the entry to the back of the cache when it
+    /// satisfies `pred` so that it is evicted next, and to the front otherwise.
+    pub fn get_and_demote_if<F: Fn(&V) -> bool>(&mut self, key: &K, pred: F) -> Option<&V> {
+        let Some(index) = self._lookup_live(key) else {
+            self.counters.record_miss();
+            return None;
+        };
+
+        self.counters.record_hit();
+        if self.entries[index].value.as_ref().is_some_and(pred) {
+            self.entries[index].last_access = self.clock.now();
+            self._move_to_back(index);
+        } else {
+            self._promote(index);
+        }
+
+        self.entries[index].value.as_ref()
+    }
+
+    /// Move the given key to the back of the cache, making it the next entry to be evicted.
+    /// Returns whether the key exists.
+    pub fn demote(&mut self, key: &K) -> bool {
+        match self.map.get(key) {
+            Some(&index) => {
+                self._move_to_back(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get the value of the given key, or on a miss copy it from the `backing` map into the cache.
+    pub fn get_or_load_from(&mut self, key: &K, backing: &mut HashMap<K, V>) -> Option<&V>
+    where
+        V: Clone,
+    {
+        if self._lookup_live(key).is_some() {
+            return self.get(key);
+        }
+
+        self.counters.record_miss();
+        let value = backing.get(key)?.clone();
+        self.put(key.clone(), value);
+
+        let index = *self.map.get(key)?;
+        self.entries[index].value.as_ref()
+    }
+
+    /// Take the value of the given key, leaving the default value in its place,
+    /// and move the entry to the front.
+    pub fn get_and_reset(&mut self, key: &K) -> Option<V>
+    where
+        V: Default,
+    {
+        let Some(index) = self._lookup_live(key) else {
+            self.counters.record_miss();
+            return None;
+        };
+
+        self.counters.record_hit();
+        self._promote(index);
+        self.entries[index].value.replace(V::default())
+    }
+
+    /// Add `delta` to the value of the given key, starting from the default value on a miss
+    /// or when the entry has expired, move the entry to the front and return the new value.
+    pub fn increment(&mut self, key: K, delta: V) -> V
+    where
+        V: Add<Output = V> + Default + Copy,
+    {
+        let current = match self._lookup_live(&key) {
+            Some(index) => self.entries[index].value.unwrap_or_default(),
+            None => V::default(),
+        };
+
+        let value = current + delta;
+        self.put(key, value);
+        value
+    }
+
+    /// Add the given key-value to the cache, expiring after `ttl`.
+    /// An expired entry is treated as absent and removed on its next access.
+    pub fn put_with_ttl(&mut self, key: K, value: V, ttl: Duration) {
+        self.put(key.clone(), value);
+
+        if let Some(&index) = self.map.get(&key) {
+            // A `ttl` too large to be represented, such as `Duration::MAX`, never expires.
+            self.entries[index].expires_at = self.entries[index].inserted_at.checked_add(ttl);
+        }
+    }
+
+    /// Add the given key-value to the cache with a tag, such as a generation or source marker.
+    /// Adding the key again with `put` clears its tag.
+    pub fn put_tagged(&mut self, key: K, value: V, tag: u64) {
+        self.put(key.clone(), value);
+
+        if let Some(&index) = self.map.get(&key) {
+            self.entries[index].tag = Some(tag);
+        }
+    }
+
+    /// Get the tag of the given key, if it was added with `put_tagged`.
+    pub fn tag_of(&self, key: &K) -> Option<u64> {
+        self.map.get(key).and_then(|&index| self.entries[index].tag)
+    }
+
+    /// Invalidate every entry carrying the given tag, returns the number of entries removed.
+    pub fn invalidate_by_tag(&mut self, tag: u64) -> usize {
+        let mut removed = 0;
+        let mut index = self.first;
+        while let Some(current) = index {
+            index = self.entries[current].next;
+
+            if self.entries[current].tag == Some(tag) {
+                self._remove(current);
+                self.counters.invalidations += 1;
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Get the value of the given key, treating an entry close to its expiry as already expired
+    /// with a probability growing as the expiry approaches (XFetch). This spreads the refresh of
+    /// entries that would otherwise all expire at the same time. The larger `beta`, the earlier
+    /// entries may expire; with a `beta` of 0 this behaves like `get`.
+    pub fn get_with_early_expiry(&mut self, key: &K, beta: f64) -> Option<&V> {
+        if let Some(&index) = self.map.get(key) {
+            let entry = &self.entries[index];
+            if let Some(expires_at) = entry.expires_at {
+                let ttl = expires_at.saturating_duration_since(entry.inserted_at);
+                let remaining = expires_at.saturating_duration_since(self.clock.now());
+
+                let roll = -self.rng.next_f64().ln() * beta * ttl.as_secs_f64();
+                if roll >= remaining.as_secs_f64() {
+                    self._remove(index);
+                }
+            }
+        }
+
+        self.get(key)
+    }
+
+    /// Seed the random generator used by probabilistic operations, to make them reproducible.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = XorShift::new(seed);
+    }
+
+    /// Get the state of the given key without changing the cache: `Expired` entries
+    /// are still stored but will be removed on their next access.
+    pub fn peek_state(&self, key: &K) -> EntryState {
+        match self.map.get(key) {
+            None => EntryState::Absent,
+            Some(&index) if self._is_expired(index) => EntryState::Expired,
+            Some(_) => EntryState::Live,
+        }
+    }
+
+    /// Remove the given key from the cache and return its value, unless it has expired.
+    /// With the `oplog` feature, only the removal of a key that was in the cache is logged.
+    pub fn pop<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self.map.remove(key)?;
+        #[cfg(feature = "oplog")]
+        self.op_log.push(Op::Invalidate(self.entries[index].key.clone()));
+        self.counters.invalidations += 1;
+        let expired = self._is_expired(index);
+        self._unlink(index);
+        self.free.push(index);
+
+        self.entries[index].value.take().filter(|_| !expired)
+    }
+
+    /// Rename the entry of the given key to the key returned by `f`, keeping its value
+    /// and its place in the recency order. An entry already stored under the new key is
+    /// removed first. Returns whether the key was in the cache.
+    pub fn modify_key<F: FnOnce(&K) -> K>(&mut self, key: &K, f: F) -> bool {
+        let Some(index) = self._lookup_live(key) else {
+            return false;
+        };
+
+        let new_key = f(&self.entries[index].key);
+        if new_key == self.entries[index].key {
+            return true;
+        }
+
+        if let Some(&colliding) = self.map.get(&new_key) {
+            self._remove(colliding);
+        }
+        self.map.remove(key);
+        self.map.insert(new_key.clone(), index);
+        self.entries[index].key = new_key;
+        true
+    }
+
+    /// Move the entry of the given key to the front of `dest`, evicting there if it is full.
+    /// The expiration, tag and pin of the entry are moved with it.
+    /// Returns whether the entry was moved: it stays in the cache if the key is missing here
+    /// or if `dest` cannot accept it, because it has a zero capacity or every entry is pinned.
+    pub fn transfer_to<S2: BuildHasher + Default>(&mut self, key: &K, dest: &mut LRUCache<K, V, S2>) -> bool {
+        let Some(index) = self._lookup_live(key) else {
+            return false;
+        };
+        if !dest._can_insert(key) {
+            return false;
+        }
+
+        let Entry { expires_at, tag, pinned, .. } = self.entries[index];
+        match self._remove(index) {
+            (key, Some(value)) => {
+                dest.put(key.clone(), value);
+                if let Some(&index) = dest.map.get(&key) {
+                    dest.entries[index].expires_at = expires_at;
+                    dest.entries[index].tag = tag;
+                    dest.entries[index].pinned = pinned;
+                }
+                true
+            }
+            (_, None) => false,
+        }
+    }
+
+    /// Add the given key-value to the cache like `put`, returning the previous value of the key
+    /// if any. If the key is new and is rejected because the cache has a zero capacity or every
+    /// entry is pinned, the key and value are given back in `Err`.
+    pub fn try_put(&mut self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+        // An expired value is not returned, as if it had already been removed.
+        let old_value = match self.map.get(&key) {
+            Some(&index) if !self._is_expired(index) => self.entries[index].value.take(),
+            _ => None,
+        };
+
+        match self._insert_evicting(key, Some(value)) {
+            Ok(_) => Ok(old_value),
+            Err((key, value)) => Err((key, value.expect("a put value is never empty"))),
+        }
+    }
+
+    /// Add the given key-value to the cache like `put`, returning the entries evicted
+    /// to make room for a new key, from the least recently used. A rejected key is dropped
+    /// and nothing is returned. There is at most one,
+    /// except while a shrink started by `set_capacity_lazy` is pending, where there can be two.
+    pub fn push(&mut self, key: K, value: V) -> Vec<(K, V)> {
+        self._insert_evicting(key, Some(value)).map_or_else(|_| Vec::new(), |(_, evicted)| evicted)
+    }
+
+    /// Set the value of the given key, moving it to the front, and return the previous value
+    /// if the key existed. A new key is inserted, evicting if the cache is full.
+    /// Same as `put`, named after the swap semantics for callers coming from other cache crates
+    /// that expose it under this name.
+    pub fn swap_or_insert(&mut self, key: K, value: V) -> Option<V> {
+        self.put(key, value)
+    }
+
+    /// Add the given key-value to the cache, replacing an existing value only if
+    /// `is_better(&value, &old_value)` is true. An expired value is treated as absent.
+    /// Returns whether the value was stored.
+    pub fn put_if_better<F: Fn(&V, &V) -> bool>(&mut self, key: K, value: V, is_better: F) -> bool {
+        let old_value = match self.map.get(&key) {
+            Some(&index) if !self._is_expired(index) => self.entries[index].value.as_ref(),
+            _ => None,
+        };
+        if old_value.is_some_and(|old_value| !is_better(&value, old_value)) {
+            return false;
+        }
+
+        self.put(key.clone(), value);
+        self.map.contains_key(&key)
+    }
+
+    /// Add the given key-value to the cache and return a mutable reference to the stored value,
+    /// or `None` if the key is new and was rejected because the cache has a zero capacity
+    /// or every entry is pinned.
+    pub fn put_mut(&mut self, key: K, value: V) -> Option<&mut V> {
+        self.put(key.clone(), value);
+
+        let index = *self.map.get(&key)?;
+        self.entries[index].value.as_mut()
+    }
+
+    /// Get the value of the given key, moving it to the front, or compute it with `f`,
+    /// add it to the cache and return the stored value. Returns `None` if the key is missing
+    /// and the computed value was rejected because the cache has a zero capacity or every
+    /// entry is pinned.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, f: F) -> Option<&V> {
+        if let Some(index) = self._lookup_live(&key) {
+            self.counters.record_hit();
+            self._promote(index);
+            return self.entries[index].value.as_ref();
+        }
+
+        self.counters.record_miss();
+        self.put_mut(key, f()).map(|value| &*value)
+    }
+
+    /// Get the value of the given key, moving it to the front, or compute it with `f`,
+    /// add it to the cache and return the stored value. When `f` fails, its error is
+    /// returned and the cache is left unchanged: nothing is added nor evicted.
+    /// Returns `Ok(None)` if the key is missing and the computed value was rejected because
+    /// the cache has a zero capacity or every entry is pinned.
+    pub fn get_or_try_insert_with<F, E>(&mut self, key: K, f: F) -> Result<Option<&V>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        if let Some(index) = self._lookup_live(&key) {
+            self.counters.record_hit();
+            self._promote(index);
+            return Ok(self.entries[index].value.as_ref());
+        }
+
+        self.counters.record_miss();
+        let value = f()?;
+        Ok(self.put_mut(key, value).map(|value| &*value))
+    }
+
+    /// Find the most recently used entry whose value satisfies `pred`, move it to the front
+    /// and return its key and value.
+    pub fn get_by_value<F: Fn(&V) -> bool>(&mut self, pred: F) -> Option<(&K, &V)> {
+        let mut index = self.first;
+        while let Some(current) = index {
+            if self.entries[current].value.as_ref().is_some_and(&pred) {
+                self._promote(current);
+
+                let entry = &self.entries[current];
+                return entry.value.as_ref().map(|value| (&entry.key, value));
+            }
+            index = self.entries[current].next;
+        }
+
+        None
+    }
+
+    /// Reserve a slot for the given key before its value is ready, moving it to the front.
+    /// The key counts as present but has no value until the slot is filled with `fill_slot`,
+    /// so concurrent callers can see that the value is already being computed.
+    /// The reservation is refused if the key already has a value: the value is kept and
+    /// `fill_slot` returns `false` for the returned token.
+    pub fn reserve_slot(&mut self, key: K) -> SlotToken<K> {
+        let has_value = self.map.get(&key).is_some_and(|&index| {
+            self.entries[index].value.is_some() && !self._is_expired(index)
+        });
+        if has_value {
+            return SlotToken { key, generation: None };
+        }
+
+        // Each reservation gets its own generation, so that a token cannot fill a later
+        // reservation of the same key, whatever slot it ends up in.
+        let generation = self._insert(key.clone(), None).map(|index| {
+            self.next_generation += 1;
+            self.entries[index].generation = self.next_generation;
+            self.next_generation
+        });
+        SlotToken { key, generation }
+    }
+
+    /// Fill a slot reserved by `reserve_slot` with its value, passed through the normalizer if any.
+    /// Returns `false`, dropping the value, if the slot was evicted, invalidated or
+    /// already filled in the meantime.
+    pub fn fill_slot(&mut self, token: SlotToken<K>, value: V) -> bool {
+        let reserved = |entry: &Entry<K, V>| Some(entry.generation) == token.generation && entry.value.is_none();
+        match self.map.get(&token.key) {
+            Some(&index) if reserved(&self.entries[index]) => {
+                let value = match &self.normalizer {
+                    Some(normalize) => normalize(value),
+                    None => value,
+                };
+                self.entries[index].value = Some(value);
+                self.counters.record_insert();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record an access to the given key that was served from elsewhere: the entry is moved
+    /// to the front and counted as a hit, without reading its value. Returns whether the key exists.
+    pub fn record_hit(&mut self, key: &K) -> bool {
+        match self._lookup_live(key) {
+            Some(index) => {
+                self.counters.record_hit();
+                self._promote(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check whether the given key is in the cache, without moving it to the front.
+    /// An expired key is not in the cache.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self._lookup(key).is_some_and(|index| !self._is_expired(index))
+    }
+
+    /// Get the value of the given key without moving it to the front.
+    /// Expired entries are not returned, but only removed on the next `get`
+    /// since the cache is not borrowed mutably.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self._lookup(key)?;
+        if self._is_expired(index) {
+            return None;
+        }
+
+        self.entries[index].value.as_ref()
+    }
+
+    /// Get the least recently used entry that is not pinned, which is the next one to be evicted,
+    /// without changing the order of the cache. Returns `None` when every entry is pinned or
+    /// when the next one to be evicted is a slot reserved with `reserve_slot` and not filled yet.
+    pub fn peek_lru(&self) -> Option<(&K, &V)> {
+        let entry = &self.entries[self._eviction_order().next()?];
+        entry.value.as_ref().map(|value| (&entry.key, value))
+    }
+
+    /// Check that every given key is in the cache, without moving them to the front.
+    pub fn contains_all(&self, keys: &[K]) -> bool {
+        keys.iter().all(|key| self.contains_key(key))
+    }
+
+    /// Check that at least one of the given keys is in the cache, without moving it to the front.
+    pub fn contains_any(&self, keys: &[K]) -> bool {
+        keys.iter().any(|key| self.contains_key(key))
+    }
+
+    /// Check whether the given key is the most recently used entry.
+    pub fn is_mru(&self, key: &K) -> bool {
+        self.map.get(key).is_some_and(|&index| Some(index) == self.first)
+    }
+
+    /// Check whether the given key is the least recently used entry.
+    pub fn is_lru(&self, key: &K) -> bool {
+        self.map.get(key).is_some_and(|&index| Some(index) == self.last)
+    }
+
+    /// Evict least recently used entries until at most `target_len` remain.
+    /// Returns the evicted key-value pairs, least recently used first.
+    /// Unlike a resize, the maximum size of the cache is left unchanged.
+    pub fn evict_to(&mut self, target_len: usize) -> Vec<(K, V)> {
+        self.flush_promotions();
+        let mut evicted = Vec::new();
+
+        while self.map.len() > target_len {
+            match self._remove_last() {
+                Some((key, Some(value))) => evicted.push((key, value)),
+                Some((_, None)) => {}
+                None => break,
+            }
+        }
+
+        evicted
+    }
+
+    /// Keep the `n` most frequently accessed entries and remove the others, the most recently
+    /// used entry winning a tie. Pinned entries are always kept. Returns the removed key-value
+    /// pairs, least recently used first.
+    pub fn keep_hottest(&mut self, n: usize) -> Vec<(K, V)> {
+        self.flush_promotions();
+        let mut ranked = Vec::with_capacity(self.map.len());
+        let mut index = self.first;
+        while let Some(current) = index {
+            ranked.push((ranked.len(), current));
+            index = self.entries[current].next;
+        }
+
+        // The sort is stable: entries with the same count stay in recency order.
+        ranked.sort_by_key(|&(_, index)| Reverse(self.entries[index].access_count));
+        let mut cold: Vec<(usize, usize)> = ranked.into_iter().skip(n).filter(|&(_, index)| !self.entries[index].pinned).collect();
+        cold.sort_by_key(|&(rank, _)| Reverse(rank));
+
+        let mut removed = Vec::with_capacity(cold.len());
+        for (_, index) in cold {
+            if let (key, Some(value)) = self._remove(index) {
+                removed.push((key, value));
+            }
+        }
+        removed
+    }
+
+    /// Split the cache into the entries matching `pred` and the others.
+    /// Both caches keep the recency order, the expirations, the clock and the maximum size
+    /// of the original one. Expired entries are dropped. The loader, if any, is not carried over.
+    pub fn partition<F: Fn(&K, &V) -> bool>(mut self, pred: F) -> (Self, Self)
+    where
+        S: Clone,
+    {
+        let mut matching = Self::with_hasher(self.max_size, self.map.hasher().clone());
+        let mut others = Self::with_hasher(self.max_size, self.map.hasher().clone());
+        matching.clock = self.clock.clone();
+        others.clock = self.clock.clone();
+
+        let mut index = self.last;
+        while let Some(current) = index {
+            index = self.entries[current].prev;
+            if self._is_expired(current) {
+                continue;
+            }
+
+            let key = self.entries[current].key.clone();
+            let expires_at = self.entries[current].expires_at;
+            if let Some(value) = self.entries[current].value.take() {
+                let target = if pred(&key, &value) { &mut matching } else { &mut others };
+                if let Some(index) = target._insert(key, Some(value)) {
+                    target.entries[index].expires_at = expires_at;
+                }
+            }
+        }
+
+        (matching, others)
+    }
+
+    /// Iterate over mutable references to the values, from the least to the most recently used,
+    /// without changing the recency order.
+    pub fn values_mut_lru(&mut self) -> impl Iterator<Item = &mut V> {
+        let mut order = Vec::with_capacity(self.map.len());
+        let mut index = self.last;
+        while let Some(current) = index {
+            order.push(current);
+            index = self.entries[current].prev;
+        }
+
+        let mut values: Vec<Option<&mut V>> = self.entries.iter_mut().map(|entry| entry.value.as_mut()).collect();
+        order.into_iter().filter_map(move |current| values[current].take())
+    }
+
+    /// Keys present in both caches, from the most to the least recently used in this cache.
+    pub fn intersection_keys<'a, S2: BuildHasher>(&'a self, other: &LRUCache<K, V, S2>) -> Vec<&'a K> {
+        let mut keys = Vec::new();
+        let mut index = self.first;
+        while let Some(current) = index {
+            let entry = &self.entries[current];
+            if other.map.contains_key(&entry.key) {
+                keys.push(&entry.key);
+            }
+            index = entry.next;
+        }
+        keys
+    }
+
+    /// Get an iterator over the entries of the cache, from the most to the least recently used,
+    /// without changing their order. Reserved slots that are not filled yet and expired entries
+    /// are skipped.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let now = self.clock.now();
+        let remaining = self.map.values().filter(|&&index| self.entries[index].live_value(now).is_some()).count();
+        Iter { entries: &self.entries, next: self.first, remaining, now }
+    }
+
+    /// Get an iterator over the keys of the cache, from the most to the least recently used.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Get an iterator over the values of the cache, from the most to the least recently used.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Fold the live entries of the cache, from the most to the least recently used.
+    pub fn fold<B, F: FnMut(B, &K, &V) -> B>(&self, init: B, mut f: F) -> B {
+        let now = self.clock.now();
+        let mut accumulator = init;
+        let mut index = self.first;
+        while let Some(current) = index {
+            let entry = &self.entries[current];
+            if let Some(value) = entry.live_value(now) {
+                accumulator = f(accumulator, &entry.key, value);
+            }
+            index = entry.next;
+        }
+        accumulator
+    }
+
+    /// Get the entries in the order their keys were first inserted, regardless of recency.
+    pub fn iter_insertion_order(&self) -> Vec<(&K, &V)> {
+        let mut entries: Vec<&Entry<K, V>> = self.map.values().map(|&index| &self.entries[index]).collect();
+        entries.sort_by_key(|entry| entry.insertion_seq);
+
+        entries
+            .into_iter()
+            .filter_map(|entry| entry.value.as_ref().map(|value| (&entry.key, value)))
+            .collect()
+    }
+
+    /// Total size of the live entries, as measured by `size_of` for each key and value.
+    pub fn live_bytes<F: Fn(&K, &V) -> usize>(&self, size_of: F) -> usize {
+        self.fold(0, |total, key, value| total + size_of(key, value))
+    }
+
+    /// Keys whose value is larger than `threshold` according to `size_of`,
+    /// from the most to the least recently used.
+    pub fn large_entries<F: Fn(&V) -> usize>(&self, threshold: usize, size_of: F) -> Vec<&K> {
+        let mut keys = Vec::new();
+        let mut index = self.first;
+        while let Some(current) = index {
+            let entry = &self.entries[current];
+            if entry.value.as_ref().is_some_and(|value| size_of(value) > threshold) {
+                keys.push(&entry.key);
+            }
+            index = entry.next;
+        }
+        keys
+    }
+
+    /// Get every live entry with its metadata, from the most to the least recently used.
+    pub fn entries_with_metadata(&self) -> Vec<EntryInfo<'_, K, V>> {
+        let mut infos = Vec::with_capacity(self.map.len());
+        let now = self.clock.now();
+
+        let mut index = self.first;
+        while let Some(current) = index {
+            let entry = &self.entries[current];
+            if let Some(value) = &entry.value {
+                infos.push(EntryInfo {
+                    key: &entry.key,
+                    value,
+                    rank: infos.len(),
+                    access_count: entry.access_count,
+                    inserted_at: entry.inserted_at,
+                    ttl_remaining: entry.expires_at.map(|expires_at| expires_at.saturating_duration_since(now)),
+                    tag: entry.tag,
+                    pinned: entry.pinned,
+                });
+            }
+            index = entry.next;
+        }
+        infos
+    }
+
+    /// Get the last access time of every entry, from the most to the least recently used.
+    pub fn last_access_times(&self) -> Vec<(&K, Instant)> {
+        let mut times = Vec::with_capacity(self.map.len());
+        let mut index = self.first;
+        while let Some(current) = index {
+            let entry = &self.entries[current];
+            times.push((&entry.key, entry.last_access));
+            index = entry.next;
+        }
+        times
+    }
+
+    /// Number of distinct values stored in the cache, lower than the number of keys
+    /// when several keys share the same value.
+    pub fn distinct_value_count(&self) -> usize
+    where
+        V: Hash + Eq,
+    {
+        self.iter().map(|(_, value)| value).collect::<HashSet<_>>().len()
+    }
+
+    /// Count the entries of the cache in each category computed from their value by `f`.
+    pub fn category_counts<C: Hash + Eq, F: Fn(&V) -> C>(&self, f: F) -> HashMap<C, usize> {
+        let mut counts = HashMap::new();
+
+        for &index in self.map.values() {
+            if let Some(value) = &self.entries[index].value {
+                *counts.entry(f(value)).or_insert(0) += 1;
+            }
+        }
+
+        counts
+    }
+
+    /// Get the `n` next entries to be evicted, in eviction order, as `(key, rank, age)` tuples
+    /// where rank 0 is the next eviction and age is the time since the last access.
+    /// Pinned entries are skipped, and entries waiting for a lazy promotion come last.
+    pub fn eviction_candidates(&self, n: usize) -> Vec<(&K, usize, Duration)> {
+        let now = self.clock.now();
+
+        self._eviction_order()
+            .take(n)
+            .enumerate()
+            .map(|(rank, index)| {
+                let entry = &self.entries[index];
+                (&entry.key, rank, now.saturating_duration_since(entry.last_access))
+            })
+            .collect()
+    }
+
+    /// Copy the `n` most recently used entries into a new cache of maximum size `n`,
+    /// keeping their recency order, their expirations and the clock. Expired entries are skipped.
+    pub fn clone_top(&self, n: usize) -> Self
+    where
+        V: Clone,
+    {
+        let mut top = Vec::with_capacity(n.min(self.map.len()));
+        let mut index = self.first;
+        while let Some(current) = index {
+            if top.len() >= n {
+                break;
+            }
+            let entry = &self.entries[current];
+            if let Some(value) = entry.value.as_ref().filter(|_| !self._is_expired(current)) {
+                top.push((entry.key.clone(), value.clone(), entry.expires_at));
+            }
+            index = entry.next;
+        }
+
+        let mut cache = Self::with_capacity(n);
+        cache.clock = self.clock.clone();
+        for (key, value, expires_at) in top.into_iter().rev() {
+            if let Some(index) = cache._insert(key, Some(value)) {
+                cache.entries[index].expires_at = expires_at;
+            }
+        }
+        cache
+    }
+
+    /// Reorder the whole cache by priority: the higher `key_fn` scores an entry, the more recently
+    /// used it becomes. Entries with the same score keep their current relative order.
+    pub fn reorder_by<F: Fn(&K, &V) -> i64>(&mut self, key_fn: F) {
+        let mut order = Vec::with_capacity(self.map.len());
+        let mut index = self.first;
+        while let Some(current) = index {
+            let entry = &self.entries[current];
+            // Reserved slots are waiting for their value, keep them hot.
+            let score = entry.value.as_ref().map_or(i64::MAX, |value| key_fn(&entry.key, value));
+            order.push((Reverse(score), current));
+            index = entry.next;
+        }
+        order.sort_by_key(|&(score, _)| score);
+
+        for (position, &(_, current)) in order.iter().enumerate() {
+            self.entries[current].prev = position.checked_sub(1).map(|prev| order[prev].1);
+            self.entries[current].next = order.get(position + 1).map(|&(_, next)| next);
+        }
+        self.first = order.first().map(|&(_, first)| first);
+        self.last = order.last().map(|&(_, last)| last);
+    }
+
+    /// Consume the cache into its key-value pairs sorted by ascending value, ignoring recency.
+    pub fn into_sorted_by_value(self) -> Vec<(K, V)>
+    where
+        V: Ord,
+    {
+        let mut pairs: Vec<(K, V)> = self.entries
+            .into_iter()
+            .filter_map(|entry| entry.value.map(|value| (entry.key, value)))
+            .collect();
+        pairs.sort_by(|(_, a), (_, b)| a.cmp(b));
+        pairs
+    }
+
+    /// Reclaim the vacant slots left behind by evicted and invalidated entries,
+    /// storing the live entries contiguously from most to least recently used.
+    pub fn compact(&mut self) {
+        self.free.clear();
+        let mut old_entries: Vec<Option<Entry<K, V>>> = self.entries.drain(..).map(Some).collect();
+        let mut index = self.first;
+
+        while let Some(old_index) = index {
+            let mut entry = old_entries[old_index].take().expect("entry linked twice");
+            index = entry.next;
+
+            let new_index = self.entries.len();
+            entry.prev = new_index.checked_sub(1);
+            entry.next = None;
+            if let Some(prev) = entry.prev {
+                self.entries[prev].next = Some(new_index);
+            }
+
+            self.map.insert(entry.key.clone(), new_index);
+            self.entries.push(entry);
+        }
+
+        self.first = if self.entries.is_empty() { None } else { Some(0) };
+        self.last = self.entries.len().checked_sub(1);
+    }
+
+    /// Rebuild the key index by walking the recency list from the first entry.
+    /// Used to recover if the index ever gets out of sync with the list.
+    /// The walk is bounded by the number of slots, so that a cycle in a corrupted list
+    /// cannot loop forever: returns `false` if the end of the list was not reached.
+    pub fn rebuild_map(&mut self) -> bool {
+        self.map.clear();
+
+        let mut index = self.first;
+        for _ in 0..self.entries.len() {
+            let Some(current) = index else { break };
+            self.map.insert(self.entries[current].key.clone(), current);
+            index = self.entries[current].next;
+        }
+        index.is_none()
+    }
+
+    /// Number of slots in the entries vector that no longer hold a live entry.
+    #[cfg(any(test, feature = "invariants"))]
+    pub fn stale_slot_count(&self) -> usize {
+        self.entries.len() - self.map.len()
+    }
+
+    /// Panic if the recency list, the map and the free slots are not consistent
+    /// with each other, or if the cache holds more keys than its capacity while
+    /// no shrink by `set_capacity_lazy` or `resize` is pending.
+    #[cfg(any(test, feature = "invariants"))]
+    pub fn check_invariants(&self) {
+        assert!(self.shrink_pending || self.map.len() <= self.max_size, "more keys than the capacity");
+
+        let mut count = 0;
+        let mut prev = None;
+        let mut index = self.first;
+        while let Some(current) = index {
+            assert!(count < self.map.len(), "the recency list is longer than the map");
+            let entry = &self.entries[current];
+            assert_eq!(entry.prev, prev, "broken backward link");
+            assert_eq!(self.map.get(&entry.key), Some(&current), "entry missing from the map");
+
+            count += 1;
+            prev = Some(current);
+            index = entry.next;
+        }
+        assert_eq!(self.last, prev, "the last entry is not the end of the list");
+        assert_eq!(count, self.map.len(), "entries of the map missing from the list");
+
+        let live_slot_freed = self.free.iter().any(|index| self.map.values().any(|used| used == index));
+        assert!(!live_slot_freed, "live slot in the free list");
+    }
+
+    /// Remove every entry for which `f` returns false. The other entries keep their order.
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut f: F) {
+        self.drain_filter(|key, value| !f(key, value)).for_each(drop);
+    }
+
+    /// Get an iterator that removes and yields every entry, from the least to the most
+    /// recently used. The cache is empty once the iterator is dropped, keeping its capacity.
+    pub fn drain(&mut self) -> Drain<'_, K, V, S> {
+        self.flush_promotions();
+        Drain { cache: self }
+    }
+
+    /// Get an iterator that removes and yields the entries matching `pred`, lazily as it
+    /// is consumed. The other entries keep their order.
+    pub fn drain_filter<F: FnMut(&K, &V) -> bool>(&mut self, pred: F) -> DrainFilter<'_, K, V, F, S> {
+        let next = self.first;
+        DrainFilter { cache: self, next, pred }
+    }
+
+    /// Get a handle over the entry of the given key, without changing its recency.
+    /// The handle remembers the entry position so that following operations
+    /// do not need to look the key up again.
+    pub fn get_entry(&mut self, key: &K) -> Option<OccupiedEntry<'_, K, V, S>> {
+        let index = self._lookup_live(key)?;
+
+        Some(OccupiedEntry { cache: self, index })
+    }
+
+    /// Remove every entry, keeping the allocated memory and the statistics.
+    pub fn clear(&mut self) {
+        #[cfg(feature = "oplog")]
+        {
+            let mut index = self.last;
+            while let Some(current) = index {
+                self.op_log.push(Op::Invalidate(self.entries[current].key.clone()));
+                index = self.entries[current].prev;
+            }
+        }
+        self.entries.clear();
+        self.map.clear();
+        self.free.clear();
+        self.first = None;
+        self.last = None;
+        self.pending_promotions = 0;
+        self.shrink_pending = false;
+    }
+
+    /// Change the maximum size of the cache, evicting the least recently used entries
+    /// when it shrinks below the current number of entries. Pinned entries are not evicted,
+    /// so the cache can stay above its new size until they are unpinned.
+    /// When shrinking, the slots left vacant are reclaimed.
+    pub fn resize(&mut self, new_capacity: usize) {
+        self.max_size = new_capacity;
+        self.flush_promotions();
+        while self.map.len() > self.max_size {
+            if self._remove_last().is_none() {
+                break;
+            }
+        }
+        // Pinned entries can keep the cache above its new size until they are unpinned.
+        self.shrink_pending = self.map.len() > self.max_size;
+
+        if self.entries.len() > new_capacity {
+            self.compact();
+            self.entries.shrink_to(new_capacity);
+        } else {
+            self.entries.reserve(new_capacity - self.entries.len());
+            self.map.reserve(new_capacity.saturating_sub(self.map.len()));
+        }
+    }
+
+    /// Change the maximum size of the cache without evicting right away. While the cache
+    /// holds more entries than the new size, each new key evicts two entries instead of one,
+    /// so the cache converges to its new size over the following insertions.
+    pub fn set_capacity_lazy(&mut self, new_capacity: usize) {
+        self.max_size = new_capacity;
+        self.shrink_pending = self.map.len() > new_capacity;
+    }
+
+    /// Number of entries that can be added before the cache starts evicting.
+    pub fn remaining_capacity(&self) -> usize {
+        self.max_size.saturating_sub(self.map.len())
+    }
+
+    /// Keys reachable by walking the recency list from the first entry.
+    /// The walk is bounded so that a cycle in the list cannot loop forever.
+    #[cfg(test)]
+    pub(crate) fn reachable_keys_forward(&self) -> Vec<K> {
+        let mut keys = Vec::new();
+        let mut index = self.first;
+        while let Some(current) = index {
+            if keys.len() > self.entries.len() {
+                break;
+            }
+            keys.push(self.entries[current].key.clone());
+            index = self.entries[current].next;
+        }
+        keys
+    }
+
+    /// Keys reachable by walking the recency list backward from the last entry.
+    #[cfg(test)]
+    pub(crate) fn reachable_keys_backward(&self) -> Vec<K> {
+        let mut keys = Vec::new();
+        let mut index = self.last;
+        while let Some(current) = index {
+            if keys.len() > self.entries.len() {
+                break;
+            }
+            keys.push(self.entries[current].key.clone());
+            index = self.entries[current].prev;
+        }
+        keys
+    }
+
+    /// Ratio of hits of `get` where each lookup counts for the weight of its entry.
+    /// A miss weighs as much as the value returned by the loader, or 1 when no value is loaded.
+    /// Without a weigher, every lookup weighs 1 and this is the hit ratio of `get`.
+    pub fn weighted_hit_ratio(&self) -> f64 {
+        self.counters.weighted_hit_ratio()
+    }
+
+    /// Evictions per lookup or insert over the recent operations. A high rate means
+    /// that the cache is too small for the working set and keeps evicting it.
+    pub fn eviction_rate(&self) -> f64 {
+        self.counters.eviction_rate()
+    }
+
+    /// Number of misses since the last hit, for callers backing off from a cold cache.
+    pub fn consecutive_misses(&self) -> u64 {
+        self.counters.consecutive_misses
+    }
+
+    /// Fingerprint of the recency order, to cheaply detect whether it changed.
+    /// The keys are hashed from the most to the least recently used with FNV-1a,
+    /// so the fingerprint is stable across runs for keys with a stable `Hash`.
+    pub fn order_fingerprint(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        let mut index = self.first;
+        while let Some(current) = index {
+            self.entries[current].key.hash(&mut hasher);
+            index = self.entries[current].next;
+        }
+        hasher.write_usize(self.map.len());
+        hasher.finish()
+    }
+
+    /// Check whether the cache has evicted an entry since it was created or its statistics were reset,
+    /// a sign that its maximum size may be too small.
+    pub fn has_evicted(&self) -> bool {
+        self.counters.evictions > 0
+    }
+
+    /// Get a copy of the cache statistics.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.counters.hits,
+            misses: self.counters.misses,
+            evictions: self.counters.evictions,
+            invalidations: self.counters.invalidations,
+            inserts: self.counters.inserts,
+            len: self.map.len(),
+            capacity: self.max_size,
+        }
+    }
+
+    /// Get a copy of the cache statistics, same as `stats`.
+    pub fn stats_snapshot(&self) -> CacheStats {
+        self.stats()
+    }
+
+    /// Reset every statistic of the cache, including the eviction rate and the weighted
+    /// hit ratio, without changing its entries.
+    pub fn reset_stats(&mut self) {
+        self.counters = Counters::default();
+    }
+
+    /// Average duration of a key lookup in `get`, in nanoseconds.
+    #[cfg(feature = "instrument")]
+    pub fn avg_lookup_ns(&self) -> f64 {
+        match self.counters.lookups.get() {
+            0 => 0.0,
+            lookups => self.counters.lookup_nanos.get() as f64 / lookups as f64,
+        }
+    }
+
+    /// Get the operations performed on the cache since it was created or the log was cleared.
+    #[cfg(feature = "oplog")]
+    pub fn op_log(&self) -> &[Op<K>] {
+        &self.op_log
+    }
+
+    /// Clear the operation log.
+    #[cfg(feature = "oplog")]
+    pub fn clear_op_log(&mut self) {
+        self.op_log.clear();
+    }
+
+    /// Describe the recency list in the Graphviz DOT language, with the `viz` feature.
+    /// Nodes are the slots of the entries labeled with their key, the first entry is
+    /// filled in green and the last one in red.
+    #[cfg(feature = "viz")]
+    pub fn to_dot(&self) -> String
+    where
+        K: std::fmt::Debug,
+    {
+        let mut dot = String::from("digraph lru {\n    rankdir=LR;\n");
+        let mut index = self.first;
+        while let Some(current) = index {
+            let entry = &self.entries[current];
+            let label = format!("{:?}", entry.key).replace('\\', "\\\\").replace('"', "\\\"");
+            let style = if Some(current) == self.first {
+                ", style=filled, fillcolor=green"
+            } else if Some(current) == self.last {
+                ", style=filled, fillcolor=red"
+            } else {
+                ""
+            };
+            dot.push_str(&format!("    n{current} [label=\"{label}\"{style}];\n"));
+
+            if let Some(next) = entry.next {
+                dot.push_str(&format!("    n{current} -> n{next} [label=\"next\"];\n"));
+            }
+            if let Some(prev) = entry.prev {
+                dot.push_str(&format!("    n{current} -> n{prev} [label=\"prev\", style=dashed];\n"));
+            }
+            index = entry.next;
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Pin the given key so that it is never evicted, returns whether the key exists.
+    /// Pinned entries still count toward the maximum size: when every entry is pinned,
+    /// new keys are rejected by `put` until an entry is unpinned or invalidated.
+    pub fn pin(&mut self, key: &K) -> bool {
+        self._set_pinned(key, true)
+    }
+
+    /// Unpin the given key so that it can be evicted again, returns whether the key exists.
+    pub fn unpin(&mut self, key: &K) -> bool {
+        self._set_pinned(key, false)
+    }
+
+    /// Check whether the given key is pinned.
+    pub fn is_pinned(&self, key: &K) -> bool {
+        self.map.get(key).is_some_and(|&index| self.entries[index].pinned)
+    }
+
+    /// Number of entries in the cache that are not pinned, and can therefore be evicted.
+    pub fn unpinned_len(&self) -> usize {
+        self.map.values().filter(|&&index| !self.entries[index].pinned).count()
+    }
+
+    fn _set_pinned(&mut self, key: &K, pinned: bool) -> bool {
+        match self.map.get(key) {
+            Some(&index) => {
+                self.entries[index].pinned = pinned;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check whether adding the given key would be accepted by `_insert_evicting`.
+    fn _can_insert(&self, key: &K) -> bool {
+        self.map.contains_key(key) || self.map.len() < self.max_size || self._eviction_order().next().is_some()
+    }
+
+    /// Add the given key and value, moving it to the front and evicting if needed.
+    /// Returns the index of the entry, or `None` if the key was rejected.
+    fn _insert(&mut self, key: K, value: Option<V>) -> Option<usize> {
+        self._insert_evicting(key, value).ok().map(|(index, _)| index)
+    }
+
+    /// Same as `_insert`, also returning the entries evicted to make room for the key.
+    /// A rejected key is given back with its value, and nothing is evicted.
+    fn _insert_evicting(&mut self, key: K, value: Option<V>) -> InsertResult<K, V> {
+        // An expired entry is replaced by a new one rather than updated, so that it does not
+        // keep the pin, access count and insertion order of the dead entry.
+        if let Some(&index) = self.map.get(&key) {
+            if self._is_expired(index) {
+                self._remove(index);
+            }
+        }
+        if !self.map.contains_key(&key) {
+            self.flush_promotions();
+            if !self._can_insert(&key) {
+                // Every entry is pinned: the new key is rejected to keep the size bounded.
+                return Err((key, value));
+            }
+        }
+        #[cfg(feature = "oplog")]
+        self.op_log.push(Op::Put(key.clone()));
+
+        // A reserved slot is only counted as an insert once it is filled.
+        let is_insert = value.is_some();
+        let value = match &self.normalizer {
+            Some(normalize) => value.map(normalize),
+            None => value,
+        };
+        if let Some(&index) = self.map.get(&key) {
+            self.entries[index].value = value;
+            self.entries[index].expires_at = None;
+            self.entries[index].tag = None;
+            let now = self.clock.now();
+            self.entries[index].last_access = now;
+            self.entries[index].inserted_at = now;
+            if self.entries[index].access_count >= self.promotion_threshold {
+                self._move_to_front(index);
+            }
+            if is_insert {
+                self.counters.record_insert();
+            }
+            return Ok((index, Vec::new()));
+        }
+
+        let mut evicted = Vec::new();
+        if self.map.len() > self.max_size {
+            // Over capacity after a lazy shrink: evict an extra entry.
+            if let Some((evicted_key, Some(evicted_value))) = self._remove_last() {
+                evicted.push((evicted_key, evicted_value));
+            }
+        }
+        if self.map.len() >= self.max_size {
+            if let Some((evicted_key, Some(evicted_value))) = self._remove_last() {
+                evicted.push((evicted_key, evicted_value));
+            }
+        }
+
+        let now = self.clock.now();
+        let new_entry = Entry {
+            key: key.clone(),
+            value,
+            prev: None,
+            next: self.first,
+            pinned: false,
+            touched: false,
+            expires_at: None,
+            tag: None,
+            last_access: now,
+            access_count: 0,
+            inserted_at: now,
+            insertion_seq: self.next_insertion_seq,
+            generation: 0,
+        };
+        self.next_insertion_seq += 1;
+
+        let new_index = match self.free.pop() {
+            Some(free_index) => {
+                self.entries[free_index] = new_entry;
+                free_index
+            }
+            None => {
+                self.entries.push(new_entry);
+                self.entries.len() - 1
+            }
+        };
+        self.map.insert(key, new_index);
+        if is_insert {
+            self.counters.record_insert();
+        }
+
+        match self.first {
+            None => {
+                self.first = Some(new_index);
+                self.last = Some(new_index);
+            }
+            Some(old_first) => {
+                self.first = Some(new_index);
+                self.entries[new_index].next = Some(old_first);
+                self.entries[old_first].prev = Some(new_index);
+            }
+        }
+        if self.promotion_threshold > 0 {
+            self._move_to_back(new_index);
+        }
+
+        self.shrink_pending = self.map.len() > self.max_size;
+        if self.map.len() >= self.max_size {
+            if let Some(on_first_full) = self.on_first_full.take() {
+                on_first_full();
+            }
+        }
+
+        Ok((new_index, evicted))
+    }
+
+    /// Remove the least recently used entry that is not pinned.
+    fn _remove_last(&mut self) -> Option<(K, Option<V>)> {
+        let mut index = self.last;
+        while let Some(current) = index {
+            if !self.entries[current].pinned {
+                self.counters.record_eviction();
+                #[cfg(feature = "oplog")]
+                self.op_log.push(Op::Evict(self.entries[current].key.clone()));
+
+                return match (self._detach(current), self.on_evict.as_mut()) {
+                    ((key, Some(value)), Some(on_evict)) => {
+                        on_evict(key.clone(), value);
+                        Some((key, None))
+                    }
+                    (evicted, _) => Some(evicted),
+                };
+            }
+            index = self.entries[current].prev;
+        }
+
+        None
+    }
+
+    fn _remove(&mut self, index: usize) -> (K, Option<V>) {
+        #[cfg(feature = "oplog")]
+        self.op_log.push(Op::Invalidate(self.entries[index].key.clone()));
+
+        self._detach(index)
+    }
+
+    /// Take the entry out of the map and the recency list, without logging the removal.
+    fn _detach(&mut self, index: usize) -> (K, Option<V>) {
+        let key = self.entries[index].key.clone();
+        self.map.remove(&key);
+        self._unlink(index);
+        self.free.push(index);
+
+        (key, self.entries[index].value.take())
+    }
+
+    fn _unlink(&mut self, index: usize) {
+        let prev = self.entries[index].prev;
+        let next = self.entries[index].next;
+
+        if Some(index) == self.first {
+            self.first = next;
+        }
+
+        if Some(index) == self.last {
+            self.last = prev;
+        }
+
+        if let Some(prev) = prev {
+            self.entries[prev].next = next;
+        }
+
+        if let Some(next) = next {
+            self.entries[next].prev = prev;
+        }
+    }
+
+    /// Find the index of the given key, timing the lookup when instrumented.
+    fn _lookup<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        #[cfg(feature = "instrument")]
+        let started = std::time::Instant::now();
+
+        let index = self.map.get(key).copied();
+
+        #[cfg(feature = "instrument")]
+        self.counters.record_lookup(started.elapsed());
+
+        index
+    }
+
+    /// Find the index of the given key if it has a value, removing it if it has expired.
+    fn _lookup_live<Q>(&mut self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self._lookup(key)?;
+        if self._is_expired(index) {
+            self._remove(index);
+            return None;
+        }
+
+        self.entries[index].value.as_ref()?;
+        Some(index)
+    }
+
+    fn _weight(&self, key: &K, value: &V) -> u64 {
+        self.weigher.as_ref().map_or(1, |weigher| weigher(key, value))
+    }
+
+    fn _is_expired(&self, index: usize) -> bool {
+        self.entries[index].expires_at.is_some_and(|expires_at| expires_at <= self.clock.now())
+    }
+
+    /// Indexes of the entries that are not pinned, in the order they would be evicted.
+    /// Entries accessed with a pending lazy promotion come last, as a flush moves them to the front.
+    fn _eviction_order(&self) -> impl Iterator<Item = usize> + '_ {
+        let from_last = move || {
+            iter::successors(self.last, move |&index| self.entries[index].prev)
+                .filter(move |&index| !self.entries[index].pinned)
+        };
+        let untouched = from_last().filter(move |&index| !self.entries[index].touched);
+        let touched = from_last().filter(move |&index| self.entries[index].touched);
+        untouched.chain(touched)
+    }
+
+    /// Record an access to the entry, moving it to the front unless promotions are lazy
+    /// or it has not reached the promotion threshold.
+    fn _promote(&mut self, index: usize) {
+        #[cfg(feature = "oplog")]
+        self.op_log.push(Op::Get(self.entries[index].key.clone()));
+
+        self.entries[index].last_access = self.clock.now();
+        self.entries[index].access_count += 1;
+        if self.entries[index].access_count < self.promotion_threshold {
+            return;
+        }
+
+        if !self.lazy_promotion {
+            self._move_to_front(index);
+        } else if !self.entries[index].touched {
+            self.entries[index].touched = true;
+            self.pending_promotions += 1;
+        }
+    }
+
+    fn _move_to_back(&mut self, index: usize) {
+        self.entries[index].touched = false;
+        if Some(index) == self.last {
+            return;
+        }
+
+        self._unlink(index);
+
+        self.entries[index].prev = self.last;
+        self.entries[index].next = None;
+        match self.last {
+            Some(old_last) => self.entries[old_last].next = Some(index),
+            None => self.first = Some(index),
+        }
+        self.last = Some(index);
+    }
+
+    fn _move_to_front(&mut self, index: usize) {
+        self.entries[index].touched = false;
+        if Some(index) == self.first {
+            return;
+        }
+
+        let prev = self.entries[index].prev;
+        let next = self.entries[index].next;
+
+        if let Some(prev) = prev {
+            self.entries[prev].next = next;
+        }
+
+        if let Some(next) = next {
+            self.entries[next].prev = prev;
+        }
+
+        if Some(index) == self.last {
+            self.last = prev;
+        }
+
+        if let Some(old_first) = self.first {
+            self.entries[old_first].prev = Some(index);
+        }
+
+        self.entries[index].prev = None;
+        self.entries[index].next = self.first;
+        self.first = Some(index);
+    }
+}
+
+#[cfg(feature = "selftest")]
+impl LRUCache<u64, u64> {
+    /// Number of distinct keys used by `self_benchmark`.
+    const BENCH_KEYS: usize = 10_000;
+
+    /// Run `ops` lookups on a cache of `BENCH_KEYS / 10` entries, adding the missing keys,
+    /// and measure the throughput. Keys follow a Zipfian distribution drawn from a fixed seed,
+    /// so that runs are comparable across machines. With the `selftest` feature.
+    pub fn self_benchmark(ops: usize) -> BenchResult {
+        // Cumulative distribution of a Zipf law of exponent 1 over the keys.
+        let mut cdf = Vec::with_capacity(Self::BENCH_KEYS);
+        let mut total = 0.0;
+        for rank in 1..=Self::BENCH_KEYS {
+            total += 1.0 / rank as f64;
+            cdf.push(total);
+        }
+
+        let mut rng = XorShift::new(0x5eed);
+        let keys: Vec<u64> = (0..ops)
+            .map(|_| {
+                let target = rng.next_f64() * total;
+                cdf.partition_point(|&weight| weight < target) as u64
+            })
+            .collect();
+
+        let mut cache = Self::with_capacity(Self::BENCH_KEYS / 10);
+        let started = Instant::now();
+        for &key in &keys {
+            if cache.get(&key).is_none() {
+                cache.put(key, key);
+            }
+        }
+        let elapsed = started.elapsed();
+
+        BenchResult {
+            ops,
+            elapsed,
+            ops_per_sec: ops as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            hit_ratio: cache.stats_snapshot().hit_ratio(),
+        }
+    }
+}
+
+/// Compare two caches by hit ratio: `Greater` means that `a` performs better than `b`.
+/// Caches that have not been accessed yet have nothing to compare and are considered equal.
+pub fn compare_efficiency<K, V, S1, S2>(a: &LRUCache<K, V, S1>, b: &LRUCache<K, V, S2>) -> Ordering
+where
+    K: Hash + Eq + Clone,
+    S1: BuildHasher + Default,
+    S2: BuildHasher + Default,
+{
+    let (a, b) = (a.stats_snapshot(), b.stats_snapshot());
+    if a.accesses() == 0 || b.accesses() == 0 {
+        return Ordering::Equal;
+    }
+
+    a.hit_ratio().total_cmp(&b.hit_ratio())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use crate::fnv::FnvHasher;
+    use std::hash::{BuildHasherDefault, Hasher};
+    use std::sync::Arc;
+    use std::sync::atomic::{self, AtomicUsize};
+
+    #[test]
+    fn test_get_put() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", String::from("A"));
+        cache.put("B", String::from("B"));
+        cache.put("C", String::from("C"));
+
+        assert_eq!(cache.get(&"A"), Some(&String::from("A")));
+        assert_eq!(cache.get(&"B"), Some(&String::from("B")));
+        assert_eq!(cache.get(&"C"), Some(&String::from("C")));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", String::from("A"));
+        cache.put("B", String::from("B"));
+        cache.put("C", String::from("C"));
+        cache.put("D", String::from("D"));
+
+        assert_eq!(cache.get(&"A"), None);
+        assert_eq!(cache.get(&"B"), Some(&String::from("B")));
+        assert_eq!(cache.get(&"C"), Some(&String::from("C")));
+        assert_eq!(cache.get(&"D"), Some(&String::from("D")));
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", String::from("A"));
+        cache.put("B", String::from("B"));
+
+        cache.invalidate(&"A");
+        assert_eq!(cache.get(&"A"), None);
+        assert_eq!(cache.get(&"B"), Some(&String::from("B")));
+    }
+
+    #[test]
+    fn test_contains_all_any() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        assert!(cache.contains_all(&["A", "B", "C"]));
+        assert!(cache.contains_any(&["A", "B", "C"]));
+
+        assert!(!cache.contains_all(&["A", "D"]));
+        assert!(cache.contains_any(&["A", "D"]));
+        assert!(!cache.contains_any(&["D", "E"]));
+
+        assert!(cache.contains_all(&[]));
+        assert!(!cache.contains_any(&[]));
+
+        // No promotion: "A" is still the least recently used entry.
+        cache.put("D", 4);
+        assert_eq!(cache.get(&"A"), None);
+    }
+
+    #[test]
+    fn test_evict_to() {
+        let mut cache = LRUCache::with_capacity(4);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.put("D", 4);
+        cache.get(&"A");
+
+        let evicted = cache.evict_to(2);
+        assert_eq!(evicted, vec![("B", 2), ("C", 3)]);
+        assert_eq!(cache.get(&"B"), None);
+        assert_eq!(cache.get(&"C"), None);
+        assert_eq!(cache.get(&"D"), Some(&4));
+        assert_eq!(cache.get(&"A"), Some(&1));
+
+        // The capacity is unchanged, so the freed room can be filled again.
+        cache.put("E", 5);
+        cache.put("F", 6);
+        assert_eq!(cache.get(&"D"), Some(&4));
+        assert_eq!(cache.get(&"A"), Some(&1));
+
+        assert!(cache.evict_to(10).is_empty());
+    }
+
+    #[test]
+    fn test_is_mru_lru() {
+        let mut cache = LRUCache::with_capacity(3);
+        assert!(!cache.is_mru(&"A"));
+        assert!(!cache.is_lru(&"A"));
+
+        cache.put("A", 1);
+        assert!(cache.is_mru(&"A"));
+        assert!(cache.is_lru(&"A"));
+
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.get(&"A");
+
+        assert!(cache.is_mru(&"A"));
+        assert!(cache.is_lru(&"B"));
+        assert!(!cache.is_mru(&"C"));
+        assert!(!cache.is_lru(&"C"));
+        assert!(!cache.is_mru(&"D"));
+    }
+
+    #[test]
+    fn test_with_loader() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let loader_calls = Arc::clone(&calls);
+        let mut cache = LRUCache::with_loader(2, move |key: &u32| {
+            loader_calls.fetch_add(1, atomic::Ordering::SeqCst);
+            if *key < 10 { Some(key * 2) } else { None }
+        });
+
+        assert_eq!(cache.get(&1), Some(&2));
+        assert_eq!(cache.get(&1), Some(&2));
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+
+        assert_eq!(cache.get(&2), Some(&4));
+        assert_eq!(cache.get(&2), Some(&4));
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), 2);
+
+        assert_eq!(cache.get(&10), None);
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), 3);
+        assert!(cache.is_mru(&2));
+    }
+
+    #[test]
+    fn test_stale_slot_count() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        assert_eq!(cache.stale_slot_count(), 0);
+
+        cache.invalidate(&"A");
+        cache.invalidate(&"B");
+        assert_eq!(cache.stale_slot_count(), 2);
+
+        cache.put("D", 4);
+        assert_eq!(cache.stale_slot_count(), 1);
+
+        cache.put("E", 5);
+        cache.put("F", 6);
+        assert_eq!(cache.stale_slot_count(), 0);
+        assert_eq!(cache.stats_snapshot().evictions, 1);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.put("D", 4);
+        cache.invalidate(&"C");
+        cache.get(&"B");
+        assert_eq!(cache.stale_slot_count(), 1);
+
+        cache.compact();
+        assert_eq!(cache.stale_slot_count(), 0);
+        assert!(cache.is_mru(&"B"));
+        assert!(cache.is_lru(&"D"));
+        assert_eq!(cache.get(&"D"), Some(&4));
+        assert_eq!(cache.get(&"B"), Some(&2));
+
+        cache.put("E", 5);
+        cache.put("F", 6);
+        assert_eq!(cache.get(&"D"), None);
+        assert_eq!(cache.get(&"B"), Some(&2));
+    }
+
+    #[test]
+    fn test_rebuild_map() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.put("D", 4);
+        cache.invalidate(&"C");
+
+        cache.map.clear();
+        assert_eq!(cache.get(&"B"), None);
+
+        assert!(cache.rebuild_map());
+        assert_eq!(cache.map.len(), 2);
+        assert_eq!(cache.get(&"A"), None);
+        assert_eq!(cache.get(&"C"), None);
+        assert_eq!(cache.get(&"B"), Some(&2));
+        assert_eq!(cache.get(&"D"), Some(&4));
+    }
+
+    #[test]
+    fn test_partition() {
+        let mut cache = LRUCache::with_capacity(4);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.put("D", 4);
+        cache.get(&"A");
+
+        let (mut even, mut odd) = cache.partition(|_, value| value % 2 == 0);
+
+        assert!(even.is_mru(&"D"));
+        assert!(even.is_lru(&"B"));
+        assert!(odd.is_mru(&"A"));
+        assert!(odd.is_lru(&"C"));
+
+        assert_eq!(even.get(&"B"), Some(&2));
+        assert_eq!(even.get(&"A"), None);
+        assert_eq!(odd.get(&"C"), Some(&3));
+        assert_eq!(odd.get(&"D"), None);
+        assert_eq!(even.max_size, 4);
+        assert_eq!(odd.max_size, 4);
+    }
+
+    thread_local! {
+        static HASH_CALLS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Key counting how many times it gets hashed.
+    #[derive(Clone, PartialEq, Eq)]
+    struct CountingKey(u32);
+
+    impl Hash for CountingKey {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            HASH_CALLS.with(|calls| calls.set(calls.get() + 1));
+            self.0.hash(state);
+        }
+    }
+
+    #[test]
+    fn test_get_entry() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put(CountingKey(1), String::from("A"));
+        cache.put(CountingKey(2), String::from("B"));
+        cache.put(CountingKey(3), String::from("C"));
+        assert!(cache.get_entry(&CountingKey(4)).is_none());
+
+        HASH_CALLS.with(|calls| calls.set(0));
+        let mut entry = cache.get_entry(&CountingKey(1)).unwrap();
+        assert_eq!(entry.key().0, 1);
+        assert_eq!(entry.get(), "A");
+        entry.get_mut().push('!');
+        assert_eq!(entry.get(), "A!");
+        entry.promote();
+        assert_eq!(HASH_CALLS.with(Cell::get), 1);
+        assert!(cache.is_mru(&CountingKey(1)));
+
+        HASH_CALLS.with(|calls| calls.set(0));
+        let entry = cache.get_entry(&CountingKey(2)).unwrap();
+        assert_eq!(entry.remove(), "B");
+        assert_eq!(HASH_CALLS.with(Cell::get), 2);
+        assert_eq!(cache.get(&CountingKey(2)), None);
+        assert_eq!(cache.get(&CountingKey(1)), Some(&String::from("A!")));
+        assert_eq!(cache.get(&CountingKey(3)), Some(&String::from("C")));
+    }
+
+    #[test]
+    fn test_pinned_eviction() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        assert!(cache.pin(&"A"));
+        assert!(!cache.pin(&"D"));
+        assert!(cache.is_pinned(&"A"));
+        assert_eq!(cache.unpinned_len(), 2);
+
+        cache.put("D", 4);
+        assert_eq!(cache.get(&"A"), Some(&1));
+        assert_eq!(cache.get(&"B"), None);
+        assert_eq!(cache.map.len(), 3);
+    }
+
+    #[test]
+    fn test_pinned_full_cache_rejects_new_keys() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.pin(&"A");
+        cache.pin(&"B");
+        assert_eq!(cache.unpinned_len(), 0);
+
+        cache.put("C", 3);
+        assert_eq!(cache.get(&"C"), None);
+        assert_eq!(cache.get(&"A"), Some(&1));
+        assert_eq!(cache.get(&"B"), Some(&2));
+        assert!(cache.evict_to(0).is_empty());
+
+        cache.put("B", 20);
+        assert_eq!(cache.get(&"B"), Some(&20));
+
+        assert!(cache.unpin(&"A"));
+        cache.put("C", 3);
+        assert_eq!(cache.get(&"A"), None);
+        assert_eq!(cache.get(&"C"), Some(&3));
+    }
+
+    #[test]
+    fn test_stats_snapshot() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.get(&"A");
+        cache.get(&"C");
+        cache.put("C", 3);
+        cache.invalidate(&"A");
+
+        let stats = cache.stats_snapshot();
+        assert_eq!(stats, CacheStats { hits: 1, misses: 1, evictions: 1, invalidations: 1, inserts: 3, len: 1, capacity: 2 });
+        assert_eq!(
+            stats.to_json(),
+            r#"{"hits":1,"misses":1,"evictions":1,"invalidations":1,"inserts":3,"len":1,"capacity":2}"#
+        );
+    }
+
+    #[test]
+    fn test_lazy_promotion() {
+        let mut cache = LRUCache::with_lazy_promotion(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        assert_eq!(cache.get(&"A"), Some(&1));
+        assert_eq!(cache.get(&"B"), Some(&2));
+        assert!(cache.is_lru(&"A"));
+        assert!(cache.is_mru(&"C"));
+
+        cache.flush_promotions();
+        assert!(cache.is_lru(&"C"));
+        assert!(cache.is_mru(&"B"));
+    }
+
+    #[test]
+    fn test_lazy_promotion_eviction() {
+        let mut cache = LRUCache::with_lazy_promotion(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.get(&"A");
+
+        cache.put("D", 4);
+        assert_eq!(cache.get(&"B"), None);
+        assert_eq!(cache.get(&"A"), Some(&1));
+        assert_eq!(cache.get(&"C"), Some(&3));
+        assert_eq!(cache.get(&"D"), Some(&4));
+    }
+
+    #[test]
+    fn test_get_if() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        assert_eq!(cache.get_if(&"A", |value| *value > 1), None);
+        assert!(cache.is_lru(&"A"));
+
+        assert_eq!(cache.get_if(&"B", |value| *value > 1), Some(&2));
+        assert!(cache.is_mru(&"B"));
+
+        assert_eq!(cache.get_if(&"D", |_| true), None);
+    }
+
+    #[test]
+    fn test_par_extend() {
+        let mut cache = LRUCache::with_capacity(50);
+        cache.par_extend((0..100).collect(), |n: u32| (n, n * n));
+
+        assert_eq!(cache.map.len(), 50);
+        assert!(cache.is_mru(&99));
+        assert!(cache.is_lru(&50));
+        assert_eq!(cache.get(&49), None);
+        assert_eq!(cache.get(&70), Some(&4900));
+    }
+
+    #[test]
+    fn test_eviction_candidates() {
+        let mut cache = LRUCache::with_capacity(5);
+        for key in ["A", "B", "C", "D", "E"] {
+            cache.put(key, ());
+        }
+        cache.get(&"A");
+        cache.pin(&"C");
+
+        let candidates = cache.eviction_candidates(3);
+        let ranks: Vec<_> = candidates.iter().map(|&(key, rank, _)| (key, rank)).collect();
+        assert_eq!(ranks, vec![(&"B", 0), (&"D", 1), (&"E", 2)]);
+        assert!(candidates[0].2 >= candidates[2].2);
+
+        assert_eq!(cache.eviction_candidates(10).len(), 4);
+        assert!(cache.eviction_candidates(0).is_empty());
+    }
+
+    #[test]
+    fn test_get_or() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+
+        assert_eq!(cache.get_or(&"C", &0), &0);
+        assert_eq!(cache.get(&"C"), None);
+
+        assert_eq!(cache.get_or(&"A", &0), &1);
+        assert!(cache.is_mru(&"A"));
+    }
+
+    #[cfg(feature = "instrument")]
+    #[test]
+    fn test_avg_lookup_ns() {
+        let mut cache = LRUCache::with_capacity(2);
+        assert_eq!(cache.avg_lookup_ns(), 0.0);
+
+        cache.put("A", 1);
+        for _ in 0..10 {
+            cache.get(&"A");
+            cache.get(&"B");
+        }
+
+        assert_eq!(cache.counters.lookups.get(), 20);
+        assert!(cache.avg_lookup_ns() > 0.0);
+    }
+
+    type FnvBuildHasher = BuildHasherDefault<FnvHasher>;
+
+    #[test]
+    fn test_with_rehashed() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.get(&"A");
+
+        let mut cache: LRUCache<_, _, FnvBuildHasher> = cache.with_rehashed(FnvBuildHasher::default());
+        assert!(cache.is_mru(&"A"));
+        assert!(cache.is_lru(&"B"));
+        assert_eq!(cache.get(&"C"), Some(&3));
+
+        cache.put("D", 4);
+        assert_eq!(cache.get(&"B"), None);
+        assert_eq!(cache.get(&"A"), Some(&1));
+        assert_eq!(cache.get(&"D"), Some(&4));
+    }
+
+    #[test]
+    fn test_get_and_demote_if() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", -2);
+        cache.put("C", 3);
+
+        assert_eq!(cache.get_and_demote_if(&"A", |value| *value < 0), Some(&1));
+        assert!(cache.is_mru(&"A"));
+
+        assert_eq!(cache.get_and_demote_if(&"B", |value| *value < 0), Some(&-2));
+        assert!(cache.is_lru(&"B"));
+
+        cache.get(&"C");
+        assert_eq!(cache.get_and_demote_if(&"C", |value| *value > 0), Some(&3));
+        assert!(cache.is_lru(&"C"));
+        assert!(cache.is_mru(&"A"));
+
+        cache.put("D", 4);
+        assert_eq!(cache.get(&"C"), None);
+        assert_eq!(cache.get(&"B"), Some(&-2));
+    }
+
+    #[test]
+    fn test_category_counts() {
+        let mut cache = LRUCache::with_capacity(4);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.put("D", 5);
+        cache.put("E", 6);
+
+        let counts = cache.category_counts(|value| value % 2 == 0);
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&true], 2);
+        assert_eq!(counts[&false], 2);
+
+        let empty: LRUCache<&str, i32> = LRUCache::with_capacity(1);
+        assert!(empty.category_counts(|value| *value).is_empty());
+    }
+
+    #[test]
+    fn test_drain_filter() {
+        let mut cache = LRUCache::with_capacity(5);
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3), ("D", 4), ("E", 5)] {
+            cache.put(key, value);
+        }
+
+        let drained: Vec<_> = cache.drain_filter(|_, value| value % 2 == 1).collect();
+        assert_eq!(drained, vec![("E", 5), ("C", 3), ("A", 1)]);
+        assert_eq!(cache.map.len(), 2);
+        assert!(cache.is_mru(&"D"));
+        assert!(cache.is_lru(&"B"));
+        assert_eq!(cache.get(&"A"), None);
+    }
+
+    #[test]
+    fn test_drain_filter_early_drop() {
+        let mut cache = LRUCache::with_capacity(5);
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3), ("D", 4), ("E", 5)] {
+            cache.put(key, value);
+        }
+
+        {
+            let mut drain = cache.drain_filter(|_, value| value % 2 == 1);
+            assert_eq!(drain.next(), Some(("E", 5)));
+        }
+
+        assert_eq!(cache.map.len(), 4);
+        assert_eq!(cache.get(&"E"), None);
+        assert_eq!(cache.get(&"C"), Some(&3));
+        assert_eq!(cache.get(&"A"), Some(&1));
+    }
+
+    #[test]
+    fn test_demote() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        assert!(cache.demote(&"C"));
+        assert!(!cache.demote(&"D"));
+        assert!(cache.is_lru(&"C"));
+        assert!(cache.is_mru(&"B"));
+
+        cache.put("D", 4);
+        assert_eq!(cache.get(&"C"), None);
+        assert_eq!(cache.get(&"A"), Some(&1));
+    }
+
+    #[test]
+    fn test_remaining_capacity() {
+        let mut cache = LRUCache::with_capacity(3);
+        assert_eq!(cache.remaining_capacity(), 3);
+
+        cache.put("A", 1);
+        assert_eq!(cache.remaining_capacity(), 2);
+
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.put("D", 4);
+        assert_eq!(cache.remaining_capacity(), 0);
+
+        let empty: LRUCache<&str, i32> = LRUCache::with_capacity(0);
+        assert_eq!(empty.remaining_capacity(), 0);
+    }
+
+    #[test]
+    fn test_get_or_load_from() {
+        let mut backing = HashMap::from([("A", 1), ("B", 2)]);
+        let mut cache = LRUCache::with_capacity(2);
+
+        assert_eq!(cache.get_or_load_from(&"A", &mut backing), Some(&1));
+        assert_eq!(cache.stats_snapshot().misses, 1);
+        assert_eq!(cache.get_or_load_from(&"A", &mut backing), Some(&1));
+        assert_eq!(cache.stats_snapshot().hits, 1);
+
+        backing.insert("A", 10);
+        assert_eq!(cache.get(&"A"), Some(&1));
+
+        assert_eq!(cache.get_or_load_from(&"C", &mut backing), None);
+        assert_eq!(cache.get(&"C"), None);
+    }
+
+    #[cfg(feature = "oplog")]
+    #[test]
+    fn test_op_log() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.get(&"A");
+        cache.put("C", 3);
+        cache.invalidate(&"A");
+
+        assert_eq!(
+            cache.op_log(),
+            &[Op::Put("A"), Op::Put("B"), Op::Get("A"), Op::Put("C"), Op::Evict("B"), Op::Invalidate("A")]
+        );
+
+        cache.clear_op_log();
+        assert!(cache.op_log().is_empty());
+        cache.get(&"C");
+        assert_eq!(cache.op_log(), &[Op::Get("C")]);
+    }
+
+    #[test]
+    fn test_increment() {
+        let mut cache = LRUCache::with_capacity(2);
+        assert_eq!(cache.increment("A", 5), 5);
+        assert_eq!(cache.increment("B", 1), 1);
+        assert_eq!(cache.increment("A", 3), 8);
+
+        assert!(cache.is_mru(&"A"));
+        assert_eq!(cache.get(&"A"), Some(&8));
+        assert_eq!(cache.get(&"B"), Some(&1));
+    }
+
+    fn assert_links_consistent<K, V, S>(cache: &LRUCache<K, V, S>)
+    where
+        K: Hash + Eq + Clone + std::fmt::Debug,
+        S: BuildHasher + Default,
+    {
+        let forward = cache.reachable_keys_forward();
+        let mut backward = cache.reachable_keys_backward();
+        backward.reverse();
+        assert_eq!(forward, backward);
+
+        assert_eq!(forward.len(), cache.map.len());
+        assert!(forward.iter().all(|key| cache.map.contains_key(key)));
+        assert!(cache.map.len() <= cache.max_size);
+    }
+
+    #[test]
+    fn test_random_operations_keep_links_consistent() {
+        let mut rng = XorShift::new(0x2545f4914f6cdd1d);
+        let mut cache = LRUCache::with_capacity(8);
+
+        for step in 0..5000 {
+            let key = rng.next_u64() % 16;
+            match rng.next_u64() % 8 {
+                0 | 1 => {
+                    cache.put(key, step);
+                }
+                2 | 3 => {
+                    cache.get(&key);
+                }
+                4 => cache.invalidate(&key),
+                5 => {
+                    cache.demote(&key);
+                }
+                6 => {
+                    if rng.next_f64() < 0.5 { cache.pin(&key) } else { cache.unpin(&key) };
+                }
+                _ => {
+                    cache.evict_to((rng.next_u64() % 8) as usize);
+                }
+            }
+
+            assert_links_consistent(&cache);
+        }
+    }
+
+    #[test]
+    fn test_clone_top() {
+        let mut cache = LRUCache::with_capacity(5);
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3), ("D", 4), ("E", 5)] {
+            cache.put(key, value);
+        }
+        cache.get(&"B");
+
+        let mut top = cache.clone_top(3);
+        assert_eq!(top.max_size, 3);
+        assert_eq!(top.reachable_keys_forward(), vec!["B", "E", "D"]);
+        assert_eq!(top.get(&"D"), Some(&4));
+        assert_eq!(cache.reachable_keys_forward(), vec!["B", "E", "D", "C", "A"]);
+    }
+
+    #[test]
+    fn test_put_mut() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put_mut("A", String::from("A")).unwrap().push('1');
+        cache.put("B", String::from("B"));
+
+        let value = cache.put_mut("C", String::from("C")).unwrap();
+        value.push('3');
+
+        assert_eq!(cache.get(&"A"), None);
+        assert_eq!(cache.get(&"C"), Some(&String::from("C3")));
+
+        cache.put_mut("B", String::from("b")).unwrap().push('2');
+        assert_eq!(cache.get(&"B"), Some(&String::from("b2")));
+    }
+
+    #[test]
+    fn test_has_evicted() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.invalidate(&"A");
+        cache.put("C", 3);
+        assert!(!cache.has_evicted());
+
+        cache.put("D", 4);
+        assert!(cache.has_evicted());
+    }
+
+    #[test]
+    fn test_fold() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.get(&"A");
+
+        assert_eq!(cache.fold(0, |sum, _, value| sum + value), 6);
+        assert_eq!(cache.fold(String::new(), |keys, key, _| keys + key), "ACB");
+    }
+
+    #[test]
+    fn test_get_by_value() {
+        let mut cache = LRUCache::with_capacity(4);
+        cache.put("A", 10);
+        cache.put("B", 20);
+        cache.put("C", 30);
+        cache.put("D", 40);
+
+        assert_eq!(cache.get_by_value(|value| *value < 25), Some((&"B", &20)));
+        assert!(cache.is_mru(&"B"));
+        assert!(cache.is_lru(&"A"));
+
+        assert_eq!(cache.get_by_value(|value| *value > 100), None);
+    }
+
+    #[test]
+    fn test_reserve_and_fill_slot() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+
+        let token = cache.reserve_slot("B");
+        assert!(cache.contains_all(&["A", "B"]));
+        assert!(cache.is_mru(&"B"));
+        assert_eq!(cache.get(&"B"), None);
+
+        assert!(cache.fill_slot(token, 2));
+        assert_eq!(cache.get(&"B"), Some(&2));
+    }
+
+    #[test]
+    fn test_slot_evicted_before_fill() {
+        let mut cache = LRUCache::with_capacity(2);
+        let token = cache.reserve_slot("A");
+        cache.put("B", 2);
+        cache.put("C", 3);
+        assert!(!cache.contains_any(&["A"]));
+
+        assert!(!cache.fill_slot(token, 1));
+        assert_eq!(cache.get(&"A"), None);
+        assert_eq!(cache.get(&"B"), Some(&2));
+        assert_eq!(cache.get(&"C"), Some(&3));
+    }
+
+    #[test]
+    fn test_intersection_keys() {
+        let mut first = LRUCache::with_capacity(4);
+        for key in ["A", "B", "C", "D"] {
+            first.put(key, 0);
+        }
+        first.get(&"B");
+
+        let mut second = LRUCache::with_capacity(4);
+        for key in ["D", "E", "B", "F"] {
+            second.put(key, 1);
+        }
+
+        assert_eq!(first.intersection_keys(&second), vec![&"B", &"D"]);
+        assert_eq!(second.intersection_keys(&first), vec![&"B", &"D"]);
+
+        let empty = LRUCache::with_capacity(1);
+        assert!(first.intersection_keys(&empty).is_empty());
+    }
+
+    #[test]
+    fn test_with_memory_budget() {
+        let entry_size = mem::size_of::<Entry<u64, u64>>();
+
+        let cache: LRUCache<u64, u64> = LRUCache::with_memory_budget(entry_size * 10 + 1);
+        assert_eq!(cache.max_size, 10);
+
+        let cache: LRUCache<u64, u64> = LRUCache::with_memory_budget(0);
+        assert_eq!(cache.max_size, 1);
+    }
+
+    #[test]
+    fn test_swap_or_insert() {
+        let mut cache = LRUCache::with_capacity(2);
+        assert_eq!(cache.swap_or_insert("A", 1), None);
+        assert_eq!(cache.swap_or_insert("B", 2), None);
+
+        assert_eq!(cache.swap_or_insert("A", 10), Some(1));
+        assert!(cache.is_mru(&"A"));
+        assert_eq!(cache.get(&"A"), Some(&10));
+
+        assert_eq!(cache.swap_or_insert("C", 3), None);
+        assert_eq!(cache.get(&"B"), None);
+        assert_eq!(cache.get(&"C"), Some(&3));
+    }
+
+    #[test]
+    fn test_peek_state() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put_with_ttl("B", 2, Duration::from_millis(10));
+        cache.put_with_ttl("C", 3, Duration::from_secs(60));
+
+        assert_eq!(cache.peek_state(&"A"), EntryState::Live);
+        assert_eq!(cache.peek_state(&"B"), EntryState::Live);
+        assert_eq!(cache.peek_state(&"D"), EntryState::Absent);
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.peek_state(&"A"), EntryState::Live);
+        assert_eq!(cache.peek_state(&"B"), EntryState::Expired);
+        assert_eq!(cache.peek_state(&"C"), EntryState::Live);
+        assert!(cache.is_mru(&"C"));
+
+        assert_eq!(cache.get(&"B"), None);
+        assert_eq!(cache.peek_state(&"B"), EntryState::Absent);
+    }
+
+    #[test]
+    fn test_reorder_by() {
+        let mut cache = LRUCache::with_capacity(5);
+        for (key, value) in [("A", 3), ("B", 1), ("C", 5), ("D", 3), ("E", 2)] {
+            cache.put(key, value);
+        }
+
+        cache.reorder_by(|_, value| *value);
+        assert_eq!(cache.reachable_keys_forward(), vec!["C", "D", "A", "E", "B"]);
+        assert_links_consistent(&cache);
+
+        cache.put("F", 0);
+        assert_eq!(cache.get(&"B"), None);
+        assert!(cache.is_mru(&"F"));
+    }
+
+    #[test]
+    fn test_put_new_keys_on_full_cache() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put(0, 0);
+        cache.put(1, 10);
+
+        for key in 2..5000 {
+            cache.put(key, key * 10);
+
+            assert_eq!(cache.get(&(key - 1)), Some(&((key - 1) * 10)));
+            assert_eq!(cache.get(&key), Some(&(key * 10)));
+            assert_eq!(cache.get(&(key - 2)), None);
+            assert_links_consistent(&cache);
+        }
+    }
+
+    #[test]
+    fn test_values_mut_lru() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", String::from("a"));
+        cache.put("B", String::from("b"));
+        cache.put("C", String::from("c"));
+        cache.get(&"A");
+
+        let mut visited = Vec::new();
+        let mut record = |value: &mut String| {
+            visited.push(value.clone());
+            value.push('!');
+        };
+        cache.values_mut_lru().for_each(&mut record);
+
+        assert_eq!(visited, vec!["b", "c", "a"]);
+        assert!(cache.is_mru(&"A"));
+        assert_eq!(cache.get(&"B"), Some(&String::from("b!")));
+    }
+
+    #[test]
+    fn test_consecutive_misses() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        assert_eq!(cache.consecutive_misses(), 0);
+
+        cache.get(&"B");
+        cache.get(&"A");
+        cache.get(&"B");
+        assert_eq!(cache.consecutive_misses(), 1);
+
+        cache.get(&"A");
+        for _ in 0..5 {
+            cache.get(&"C");
+        }
+        assert_eq!(cache.consecutive_misses(), 5);
+
+        cache.get(&"A");
+        assert_eq!(cache.consecutive_misses(), 0);
+    }
+
+    #[test]
+    fn test_put_tagged() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put_tagged("A", 1, 7);
+        cache.put_tagged("B", 2, 8);
+        cache.put("C", 3);
+
+        assert_eq!(cache.tag_of(&"A"), Some(7));
+        assert_eq!(cache.tag_of(&"B"), Some(8));
+        assert_eq!(cache.tag_of(&"C"), None);
+        assert_eq!(cache.tag_of(&"D"), None);
+        assert!(cache.is_mru(&"C"));
+
+        cache.put("A", 10);
+        assert_eq!(cache.tag_of(&"A"), None);
+        cache.put_tagged("C", 30, 9);
+        assert_eq!(cache.tag_of(&"C"), Some(9));
+        assert_eq!(cache.get(&"C"), Some(&30));
+    }
+
+    #[test]
+    fn test_invalidate_by_tag() {
+        let mut cache = LRUCache::with_capacity(5);
+        cache.put_tagged("A", 1, 1);
+        cache.put_tagged("B", 2, 2);
+        cache.put_tagged("C", 3, 1);
+        cache.put("D", 4);
+        cache.put_tagged("E", 5, 1);
+
+        assert_eq!(cache.invalidate_by_tag(1), 3);
+        assert_eq!(cache.reachable_keys_forward(), vec!["D", "B"]);
+        assert_links_consistent(&cache);
+        assert_eq!(cache.stats_snapshot().invalidations, 3);
+
+        assert_eq!(cache.invalidate_by_tag(1), 0);
+        assert_eq!(cache.tag_of(&"B"), Some(2));
+    }
+
+    #[test]
+    fn test_order_fingerprint() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        let fingerprint = cache.order_fingerprint();
+        assert!(cache.contains_all(&["A", "B"]));
+        cache.get(&"C");
+        assert_eq!(cache.order_fingerprint(), fingerprint);
+
+        cache.get(&"A");
+        assert_ne!(cache.order_fingerprint(), fingerprint);
+
+        let mut same_order = LRUCache::with_capacity(3);
+        same_order.put("B", 20);
+        same_order.put("C", 30);
+        same_order.put("A", 10);
+        assert_eq!(same_order.order_fingerprint(), cache.order_fingerprint());
+    }
+
+    #[test]
+    fn test_put_if_better() {
+        let mut cache = LRUCache::with_capacity(2);
+        assert!(cache.put_if_better("A", 10, |new, old| new < old));
+        assert!(cache.put_if_better("B", 5, |new, old| new < old));
+
+        assert!(!cache.put_if_better("A", 12, |new, old| new < old));
+        assert_eq!(cache.get(&"A"), Some(&10));
+
+        cache.get(&"B");
+        assert!(cache.put_if_better("A", 7, |new, old| new < old));
+        assert!(cache.is_mru(&"A"));
+        assert_eq!(cache.get(&"A"), Some(&7));
+    }
+
+    #[test]
+    fn test_last_access_times() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.get(&"A");
+
+        thread::sleep(Duration::from_millis(20));
+        cache.get(&"B");
+
+        let times = cache.last_access_times();
+        let keys: Vec<_> = times.iter().map(|&(key, _)| *key).collect();
+        assert_eq!(keys, vec!["B", "A", "C"]);
+
+        let (b, a, c) = (times[0].1, times[1].1, times[2].1);
+        assert!(b.duration_since(a) >= Duration::from_millis(20));
+        assert!(a >= c);
+    }
+
+    #[test]
+    fn test_into_sorted_by_value() {
+        let mut cache = LRUCache::with_capacity(4);
+        for (key, value) in [("A", 30), ("B", 10), ("C", 40), ("D", 20), ("E", 50)] {
+            cache.put(key, value);
+        }
+        cache.invalidate(&"C");
+
+        assert_eq!(cache.into_sorted_by_value(), vec![("B", 10), ("D", 20), ("E", 50)]);
+    }
+
+    #[test]
+    fn test_compare_efficiency() {
+        let mut good = LRUCache::with_capacity(2);
+        let mut bad = LRUCache::with_capacity(2);
+        let idle: LRUCache<&str, i32> = LRUCache::with_capacity(2);
+        assert_eq!(compare_efficiency(&good, &bad), Ordering::Equal);
+
+        good.put("A", 1);
+        bad.put("A", 1);
+        for key in ["A", "A", "A", "B"] {
+            good.get(&key);
+        }
+        for key in ["A", "B", "C", "D"] {
+            bad.get(&key);
+        }
+
+        assert_eq!(compare_efficiency(&good, &bad), Ordering::Greater);
+        assert_eq!(compare_efficiency(&bad, &good), Ordering::Less);
+        assert_eq!(compare_efficiency(&good, &good), Ordering::Equal);
+        assert_eq!(compare_efficiency(&good, &idle), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_record_hit() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        assert!(cache.record_hit(&"A"));
+        assert!(cache.record_hit(&"A"));
+        assert!(!cache.record_hit(&"D"));
+
+        assert!(cache.is_mru(&"A"));
+        assert_eq!(cache.entries[cache.map[&"A"]].access_count, 2);
+        assert_eq!(cache.entries[cache.map[&"B"]].access_count, 0);
+        assert_eq!(cache.stats_snapshot().hits, 2);
+    }
+
+    #[test]
+    fn test_large_entries() {
+        let mut cache = LRUCache::with_capacity(4);
+        cache.put("A", String::from("a long value"));
+        cache.put("B", String::from("tiny"));
+        cache.put("C", String::from("another long value"));
+        cache.put("D", String::from("short"));
+
+        assert_eq!(cache.large_entries(5, String::len), vec![&"C", &"A"]);
+        assert!(cache.large_entries(100, String::len).is_empty());
+    }
+
+    #[test]
+    fn test_get_with_early_expiry() {
+        let mut cache = LRUCache::with_capacity(200);
+        cache.seed_rng(42);
+        for key in 0..200 {
+            cache.put_with_ttl(key, key, Duration::from_secs(100));
+        }
+
+        // Entries at the start of their lifetime are almost never expired early.
+        for key in 0..100 {
+            assert_eq!(cache.get_with_early_expiry(&key, 0.1), Some(&key));
+        }
+
+        // Entries one millisecond away from their expiry almost always are.
+        for key in 100..200 {
+            let index = cache.map[&key];
+            let now = Instant::now();
+            cache.entries[index].inserted_at = now - Duration::from_secs(100);
+            cache.entries[index].expires_at = Some(now + Duration::from_millis(1));
+        }
+        let expired = (100..200).filter(|key| cache.get_with_early_expiry(key, 0.1).is_none()).count();
+        assert!(expired > 95);
+
+        cache.put_with_ttl(0, 0, Duration::from_millis(1));
+        assert_eq!(cache.get_with_early_expiry(&0, 0.0), Some(&0));
+        cache.put(1, 1);
+        assert_eq!(cache.get_with_early_expiry(&1, 1000.0), Some(&1));
+    }
+
+    #[test]
+    fn test_iter_insertion_order() {
+        let mut cache = LRUCache::with_capacity(4);
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3), ("D", 4)] {
+            cache.put(key, value);
+        }
+        cache.get(&"B");
+        cache.get(&"A");
+        cache.put("C", 30);
+        cache.invalidate(&"D");
+        cache.put("E", 5);
+
+        assert_eq!(cache.iter_insertion_order(), vec![(&"A", &1), (&"B", &2), (&"C", &30), (&"E", &5)]);
+    }
+
+    #[test]
+    fn test_entries_bounded_after_evictions() {
+        let mut cache = LRUCache::with_capacity(100);
+        for key in 0..100_000 {
+            cache.put(key, key);
+            if key % 3 == 0 {
+                cache.invalidate(&(key / 2));
+            }
+        }
+
+        assert!(cache.entries.len() <= 100);
+        assert_eq!(cache.get(&99_999), Some(&99_999));
+        assert_links_consistent(&cache);
+    }
+
+    #[test]
+    fn test_len() {
+        let mut cache = LRUCache::with_capacity(3);
+        assert_eq!(cache.len(), 0);
+
+        for key in 0..5 {
+            cache.put(key, key);
+        }
+        assert_eq!(cache.len(), 3);
+
+        cache.invalidate(&4);
+        cache.invalidate(&4);
+        assert_eq!(cache.len(), 2);
+
+        cache.invalidate(&3);
+        cache.invalidate(&2);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_live_bytes() {
+        let mut cache = LRUCache::with_capacity(3);
+        assert_eq!(cache.live_bytes(|key: &String, value: &String| key.len() + value.len()), 0);
+
+        cache.put(String::from("a"), String::from("hello"));
+        cache.put(String::from("bb"), String::from("world!"));
+        cache.put(String::from("ccc"), String::new());
+        cache.put(String::from("dddd"), String::from("xyz"));
+        cache.invalidate(&String::from("ccc"));
+
+        assert_eq!(cache.live_bytes(|key, value| key.len() + value.len()), 15);
+        assert_eq!(cache.live_bytes(|_, value| value.len()), 9);
+    }
+
+    #[test]
+    fn test_promotion_threshold() {
+        let mut plain = LRUCache::with_capacity(3);
+        let mut resistant = LRUCache::with_promotion_threshold(3, 2);
+        for cache in [&mut plain, &mut resistant] {
+            cache.put(-1, -1);
+            cache.get(&-1);
+            cache.get(&-1);
+            for key in 0..100 {
+                cache.put(key, key);
+            }
+            assert_links_consistent(cache);
+        }
+
+        assert_eq!(plain.get(&-1), None);
+        assert_eq!(resistant.get(&-1), Some(&-1));
+        assert!(resistant.is_mru(&-1));
+        assert!(resistant.is_lru(&99));
+
+        resistant.get(&99);
+        assert!(resistant.is_lru(&99));
+        resistant.get(&99);
+        assert!(resistant.is_mru(&99));
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut cache = LRUCache::with_capacity(2);
+        assert!(cache.is_empty());
+
+        cache.put("A", 1);
+        assert!(!cache.is_empty());
+
+        cache.invalidate(&"A");
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_get_and_reset() {
+        let mut cache = LRUCache::with_capacity(2);
+        assert_eq!(cache.get_and_reset(&"A"), None);
+
+        for delta in [3, 4, 5] {
+            cache.increment("A", delta);
+        }
+        cache.put("B", 1);
+
+        assert_eq!(cache.get_and_reset(&"A"), Some(12));
+        assert!(cache.is_mru(&"A"));
+        assert_eq!(cache.get(&"A"), Some(&0));
+
+        cache.increment("A", 2);
+        assert_eq!(cache.get_and_reset(&"A"), Some(2));
+    }
+
+    #[test]
+    fn test_capacity_getter() {
+        let mut cache = LRUCache::with_capacity(7);
+        assert_eq!(cache.capacity(), 7);
+
+        for key in 0..10 {
+            cache.put(key, key);
+        }
+        assert_eq!(cache.capacity(), 7);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut cache = LRUCache::with_capacity(3);
+        for key in 0..5 {
+            cache.put(key, key);
+        }
+        cache.invalidate(&3);
+        let allocated = cache.entries.capacity();
+
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.capacity(), 3);
+        assert_eq!(cache.entries.capacity(), allocated);
+        for key in 0..5 {
+            assert_eq!(cache.get(&key), None);
+        }
+
+        for key in 10..14 {
+            cache.put(key, key);
+        }
+        assert_eq!(cache.get(&10), None);
+        assert_eq!(cache.get(&13), Some(&13));
+        assert_eq!(cache.len(), 3);
+        assert_links_consistent(&cache);
+    }
+
+    /// Wrapper checking the invariants of the cache after every operation.
+    struct CheckedCache<K, V> {
+        cache: LRUCache<K, V>,
+        // Slots can only exceed the capacity when pinned entries kept the cache above it on a resize.
+        slot_limit: usize,
+    }
+
+    impl<K: Hash + Eq + Clone, V> CheckedCache<K, V> {
+        fn with_capacity(max_size: usize) -> Self {
+            CheckedCache { cache: LRUCache::with_capacity(max_size), slot_limit: max_size }
+        }
+
+        fn check(&self) {
+            self.cache.check_invariants();
+            assert!(self.cache.stale_slot_count() <= self.slot_limit);
+        }
+
+        fn put(&mut self, key: K, value: V) {
+            self.cache.put(key, value);
+            self.check();
+        }
+
+        fn get(&mut self, key: &K) -> bool {
+            let found = self.cache.get(key).is_some();
+            self.check();
+            found
+        }
+
+        fn invalidate(&mut self, key: &K) {
+            self.cache.invalidate(key);
+            self.check();
+        }
+
+        fn resize(&mut self, new_capacity: usize) {
+            self.cache.resize(new_capacity);
+            self.slot_limit = new_capacity.max(self.cache.len());
+            self.check();
+        }
+    }
+
+    #[test]
+    fn test_checked_random_operations() {
+        let mut rng = XorShift::new(0x9e3779b97f4a7c15);
+        let mut cache = CheckedCache::with_capacity(16);
+
+        for step in 0..10_000 {
+            let key = rng.next_u64() % 40;
+            match rng.next_u64() % 11 {
+                0..=3 => cache.put(key, step),
+                4..=6 => {
+                    cache.get(&key);
+                }
+                7 => cache.invalidate(&key),
+                8 => {
+                    cache.cache.put_with_ttl(key, step, Duration::ZERO);
+                    cache.check();
+                }
+                9 => cache.resize((rng.next_u64() % 24) as usize),
+                _ => {
+                    if rng.next_f64() < 0.5 { cache.cache.pin(&key) } else { cache.cache.unpin(&key) };
+                    cache.check();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        for _ in 0..5 {
+            assert!(cache.contains_key(&"A"));
+        }
+        assert!(!cache.contains_key(&"D"));
+
+        cache.put("D", 4);
+        assert!(!cache.contains_key(&"A"));
+        assert!(cache.contains_key(&"B"));
+    }
+
+    #[test]
+    fn test_entries_with_metadata() {
+        let started = Instant::now();
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put_tagged("B", 2, 7);
+        cache.put_with_ttl("C", 3, Duration::from_secs(60));
+        cache.get(&"A");
+        cache.get(&"A");
+        cache.pin(&"B");
+
+        let infos = cache.entries_with_metadata();
+        let keys: Vec<_> = infos.iter().map(|info| *info.key).collect();
+        assert_eq!(keys, vec!["A", "C", "B"]);
+        assert!(infos.iter().enumerate().all(|(rank, info)| info.rank == rank));
+        assert!(infos.iter().all(|info| info.inserted_at >= started));
+
+        assert_eq!((infos[0].value, infos[0].access_count, infos[0].tag), (&1, 2, None));
+        assert!(infos[0].ttl_remaining.is_none());
+        assert!(infos[1].ttl_remaining.is_some_and(|ttl| ttl > Duration::from_secs(50)));
+        assert_eq!((infos[2].tag, infos[2].pinned), (Some(7), true));
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        for _ in 0..5 {
+            assert_eq!(cache.peek(&"A"), Some(&1));
+        }
+        assert_eq!(cache.peek(&"D"), None);
+
+        cache.put("D", 4);
+        assert_eq!(cache.peek(&"A"), None);
+        assert_eq!(cache.peek(&"B"), Some(&2));
+
+        cache.put_with_ttl("E", 5, Duration::ZERO);
+        assert_eq!(cache.peek(&"E"), None);
+    }
+
+    #[test]
+    fn test_set_capacity_lazy() {
+        let mut cache = LRUCache::with_capacity(10);
+        for key in 0..10 {
+            cache.put(key, key);
+        }
+
+        cache.set_capacity_lazy(4);
+        assert_eq!((cache.len(), cache.capacity()), (10, 4));
+
+        for (key, expected_len) in (10..20).zip([9, 8, 7, 6, 5, 4, 4, 4, 4, 4]) {
+            cache.put(key, key);
+            assert_eq!(cache.len(), expected_len);
+        }
+        assert_eq!(cache.reachable_keys_forward(), vec![19, 18, 17, 16]);
+    }
+
+    #[test]
+    fn test_peek_lru() {
+        let mut cache = LRUCache::with_capacity(3);
+        assert_eq!(cache.peek_lru(), None);
+
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        assert_eq!(cache.peek_lru(), Some((&"A", &1)));
+
+        cache.get(&"A");
+        cache.get(&"C");
+        assert_eq!(cache.peek_lru(), Some((&"B", &2)));
+        assert_eq!(cache.peek_lru(), Some((&"B", &2)));
+
+        cache.put("D", 4);
+        assert!(!cache.contains_key(&"B"));
+        assert_eq!(cache.peek_lru(), Some((&"A", &1)));
+
+        cache.put("E", 5);
+        assert!(!cache.contains_key(&"A"));
+    }
+
+    #[test]
+    fn test_eviction_rate() {
+        let mut small = LRUCache::with_capacity(4);
+        let mut large = LRUCache::with_capacity(64);
+        assert_eq!(small.eviction_rate(), 0.0);
+
+        for step in 0..3000 {
+            let key = step % 50;
+            for cache in [&mut small, &mut large] {
+                if cache.get(&key).is_none() {
+                    cache.put(key, step);
+                }
+            }
+        }
+        assert!(small.eviction_rate() > 0.4);
+        assert!(large.eviction_rate() < 0.01);
+
+        for _ in 0..3000 {
+            small.get(&49);
+        }
+        assert_eq!(small.eviction_rate(), 0.0);
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", String::from("Hello"));
+        cache.put("B", String::from("B"));
+        assert_eq!(cache.get_mut(&"C"), None);
+
+        cache.get_mut(&"A").unwrap().push_str(", world");
+        assert!(cache.is_mru(&"A"));
+
+        cache.put("C", String::from("C"));
+        assert_eq!(cache.get(&"A"), Some(&String::from("Hello, world")));
+        assert_eq!(cache.get(&"B"), None);
+    }
+
+    #[test]
+    fn test_keep_hottest() {
+        let mut cache = LRUCache::with_capacity(5);
+        for (key, accesses) in [("A", 3), ("B", 0), ("C", 1), ("D", 3), ("E", 1)] {
+            cache.put(key, accesses);
+            for _ in 0..accesses {
+                cache.get(&key);
+            }
+        }
+
+        let removed = cache.keep_hottest(3);
+        assert_eq!(removed, vec![("B", 0), ("C", 1)]);
+        assert_eq!(cache.reachable_keys_forward(), vec!["E", "D", "A"]);
+
+        assert_eq!(cache.keep_hottest(5), vec![]);
+        assert_eq!(cache.keep_hottest(0).len(), 3);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_on_first_full() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut cache = LRUCache::with_capacity(3);
+        let counter = calls.clone();
+        cache.on_first_full(move || {
+            counter.fetch_add(1, atomic::Ordering::SeqCst);
+        });
+
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("A", 3);
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), 0);
+
+        cache.put("C", 4);
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+
+        for key in ["D", "E", "F", "G"] {
+            cache.put(key, 5);
+        }
+        cache.clear();
+        for key in ["A", "B", "C", "D"] {
+            cache.put(key, 6);
+        }
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_put_returns_previous_value() {
+        let mut cache = LRUCache::with_capacity(2);
+        assert_eq!(cache.put("A", String::from("first")), None);
+        assert_eq!(cache.put("B", String::from("other")), None);
+
+        assert_eq!(cache.put("A", String::from("second")), Some(String::from("first")));
+        assert_eq!(cache.put("A", String::from("third")), Some(String::from("second")));
+        assert_eq!(cache.get(&"A"), Some(&String::from("third")));
+
+        cache.invalidate(&"B");
+        assert_eq!(cache.put("B", String::from("again")), None);
+        cache.put_with_ttl("C", String::from("expired"), Duration::ZERO);
+        assert_eq!(cache.put("C", String::from("fresh")), None);
+    }
+
+    #[test]
+    fn test_push() {
+        let mut cache = LRUCache::with_capacity(3);
+        assert_eq!(cache.push("A", 1), vec![]);
+        assert_eq!(cache.push("B", 2), vec![]);
+        assert_eq!(cache.push("C", 3), vec![]);
+        assert_eq!(cache.push("A", 10), vec![]);
+
+        let lru = cache.peek_lru().map(|(key, value)| (*key, *value));
+        assert_eq!(lru, Some(("B", 2)));
+        assert_eq!(cache.push("D", 4), Vec::from_iter(lru));
+        assert_eq!(cache.push("E", 5), vec![("C", 3)]);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_push_after_lazy_shrink() {
+        let mut cache = LRUCache::with_capacity(3);
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3)] {
+            cache.push(key, value);
+        }
+        cache.set_capacity_lazy(2);
+
+        assert_eq!(cache.push("D", 4), vec![("A", 1), ("B", 2)]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.push("E", 5), vec![("C", 3)]);
+    }
+
+    #[test]
+    fn test_transfer_to() {
+        let mut hot = LRUCache::with_capacity(2);
+        let mut cold = LRUCache::with_capacity(2);
+        hot.put("A", 1);
+        hot.put("B", 2);
+        cold.put("X", 10);
+        cold.put("Y", 20);
+
+        assert!(hot.transfer_to(&"A", &mut cold));
+        assert!(!hot.contains_key(&"A"));
+        assert_eq!(hot.len(), 1);
+        assert!(cold.is_mru(&"A"));
+        assert_eq!(cold.peek(&"A"), Some(&1));
+        assert!(!cold.contains_key(&"X"));
+
+        assert!(!hot.transfer_to(&"A", &mut cold));
+        assert_links_consistent(&hot);
+        assert_links_consistent(&cold);
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", String::from("A"));
+        cache.put("B", String::from("B"));
+
+        assert_eq!(cache.pop(&"A"), Some(String::from("A")));
+        assert_eq!(cache.pop(&"A"), None);
+        assert_eq!(cache.pop(&"C"), None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.stats_snapshot().invalidations, 1);
+
+        cache.put_with_ttl("D", String::from("D"), Duration::ZERO);
+        assert_eq!(cache.pop(&"D"), None);
+        assert!(!cache.contains_key(&"D"));
+        assert_links_consistent(&cache);
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let calls = Cell::new(0);
+        let compute = |value: i32| {
+            calls.set(calls.get() + 1);
+            value
+        };
+
+        let mut cache = LRUCache::with_capacity(2);
+        assert_eq!(cache.get_or_insert_with("A", || compute(1)), Some(&1));
+        assert_eq!(cache.get_or_insert_with("A", || compute(2)), Some(&1));
+        assert_eq!(calls.get(), 1);
+
+        cache.put("B", 2);
+        assert_eq!(cache.get_or_insert_with("C", || compute(3)), Some(&3));
+        assert_eq!(calls.get(), 2);
+        assert!(!cache.contains_key(&"A"));
+        assert_eq!(cache.stats_snapshot().hits, 1);
+    }
+
+    #[test]
+    fn test_weighted_hit_ratio() {
+        let mut cache = LRUCache::with_weigher(4, |_, value: &String| value.len() as u64);
+        assert_eq!(cache.weighted_hit_ratio(), 0.0);
+
+        cache.put("big", "x".repeat(97));
+        cache.put("small", String::from("x"));
+        for _ in 0..3 {
+            cache.get(&"big");
+            cache.get(&"missing");
+        }
+        cache.get(&"small");
+
+        let stats = cache.stats_snapshot();
+        assert_eq!((stats.hits, stats.misses), (4, 3));
+        assert_eq!(cache.weighted_hit_ratio(), 292.0 / 295.0);
+        assert!(cache.weighted_hit_ratio() > stats.hit_ratio());
+
+        let mut unweighted = LRUCache::with_capacity(2);
+        unweighted.put("A", 1);
+        unweighted.get(&"A");
+        unweighted.get(&"B");
+        assert_eq!(unweighted.weighted_hit_ratio(), 0.5);
+    }
+
+    #[test]
+    fn test_get_or_try_insert_with() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+
+        assert_eq!(cache.get_or_try_insert_with("C", || Err("failed")), Err("failed"));
+        assert_eq!(cache.reachable_keys_forward(), vec!["B", "A"]);
+        assert_eq!(cache.len(), 2);
+
+        assert_eq!(cache.get_or_try_insert_with("A", || Err("not called")), Ok(Some(&1)));
+        assert_eq!(cache.get_or_try_insert_with::<_, ()>("C", || Ok(3)), Ok(Some(&3)));
+        assert_eq!(cache.reachable_keys_forward(), vec!["C", "A"]);
+    }
+
+    #[cfg(feature = "viz")]
+    #[test]
+    fn test_to_dot() {
+        let mut cache = LRUCache::with_capacity(3);
+        assert_eq!(cache.to_dot(), "digraph lru {\n    rankdir=LR;\n}\n");
+
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        let dot = cache.to_dot();
+        assert!(dot.starts_with("digraph lru {"));
+        assert!(dot.contains("n2 [label=\"\\\"C\\\"\", style=filled, fillcolor=green];"));
+        assert!(dot.contains("n1 [label=\"\\\"B\\\"\"];"));
+        assert!(dot.contains("n0 [label=\"\\\"A\\\"\", style=filled, fillcolor=red];"));
+        assert!(dot.contains("n2 -> n1 [label=\"next\"];"));
+        assert!(dot.contains("n1 -> n0 [label=\"next\"];"));
+        assert!(dot.contains("n0 -> n1 [label=\"prev\", style=dashed];"));
+        assert!(!dot.contains("n0 -> n2"));
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut cache = LRUCache::with_capacity(5);
+        for key in ["A", "B", "C", "D", "E"] {
+            cache.put(key, 0);
+        }
+        cache.get(&"A");
+
+        cache.resize(2);
+        assert_eq!(cache.capacity(), 2);
+        assert_eq!(cache.reachable_keys_forward(), vec!["A", "E"]);
+        assert_eq!(cache.stats_snapshot().evictions, 3);
+
+        cache.resize(4);
+        for key in ["F", "G"] {
+            cache.put(key, 0);
+        }
+        assert_eq!(cache.reachable_keys_forward(), vec!["G", "F", "A", "E"]);
+        cache.put("H", 0);
+        assert!(!cache.contains_key(&"E"));
+        assert_links_consistent(&cache);
+    }
+
+    #[test]
+    fn test_modify_key() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put(String::from("a"), 1);
+        cache.put(String::from("b"), 2);
+        cache.put(String::from("c"), 3);
+
+        assert!(cache.modify_key(&String::from("b"), |key| key.to_uppercase()));
+        assert_eq!(cache.reachable_keys_forward(), vec!["c", "B", "a"]);
+        assert_eq!(cache.peek(&String::from("B")), Some(&2));
+        assert!(!cache.contains_key(&String::from("b")));
+        assert!(!cache.modify_key(&String::from("z"), |key| key.clone()));
+
+        assert!(cache.modify_key(&String::from("a"), |_| String::from("c")));
+        assert_eq!(cache.reachable_keys_forward(), vec!["B", "c"]);
+        assert_eq!(cache.peek(&String::from("c")), Some(&1));
+        assert_links_consistent(&cache);
+    }
+
+    #[test]
+    fn test_get_with_idle() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        assert_eq!(cache.get_with_idle(&"C"), None);
+
+        thread::sleep(Duration::from_millis(20));
+        let (value, idle) = cache.get_with_idle(&"A").unwrap();
+        assert_eq!(value, &1);
+        assert!(idle >= Duration::from_millis(20));
+        assert!(cache.is_mru(&"A"));
+
+        let (_, idle) = cache.get_with_idle(&"A").unwrap();
+        assert!(idle < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_default() {
+        let mut cache: LRUCache<i32, i32> = LRUCache::default();
+        assert_eq!(cache.capacity(), DEFAULT_CAPACITY);
+        assert!(cache.is_empty());
+
+        cache.put(1, 1);
+        assert_eq!(cache.get(&1), Some(&1));
+
+        let cache: LRUCache<i32, i32, FnvBuildHasher> = Default::default();
+        assert_eq!(cache.capacity(), 16);
+    }
+
+    #[test]
+    fn test_put_dedup() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("X", 0);
+        cache.put("Y", 0);
+
+        cache.put_dedup([("A", 1), ("B", 2), ("A", 3), ("A", 4), ("B", 5)]);
+        assert_eq!(cache.reachable_keys_forward(), vec!["B", "A", "Y"]);
+        assert_eq!(cache.peek(&"A"), Some(&4));
+        assert_eq!(cache.peek(&"B"), Some(&5));
+        assert_eq!(cache.stats_snapshot().evictions, 1);
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", String::from("A"));
+        cache.put("B", String::from("B"));
+        cache.put("C", String::from("C"));
+        cache.get(&"A");
+
+        let mut copy = cache.clone();
+        assert_eq!(copy.reachable_keys_forward(), cache.reachable_keys_forward());
+        assert_eq!(copy.stats_snapshot(), cache.stats_snapshot());
+
+        assert_eq!(cache.push("D", String::from("D")), vec![("B", String::from("B"))]);
+        assert_eq!(copy.push("D", String::from("D")), vec![("B", String::from("B"))]);
+
+        copy.put("A", String::from("changed"));
+        assert_eq!(cache.peek(&"A"), Some(&String::from("A")));
+        assert_links_consistent(&copy);
+    }
+
+    #[test]
+    fn test_policy() {
+        let cache: LRUCache<i32, i32> = LRUCache::with_capacity(1);
+        assert_eq!(cache.policy(), CachePolicy::Lru);
+
+        let cache: LRUCache<i32, i32> = LRUCache::with_lazy_promotion(1);
+        assert_eq!(cache.policy(), CachePolicy::Lru);
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.get(&"A");
+        cache.get(&"B");
+        cache.invalidate(&"C");
+
+        assert_eq!(format!("{:?}", cache), r#"LRUCache { capacity: 3, entries: {"B": 2, "A": 1} }"#);
+    }
+
+    #[test]
+    fn test_value_normalizer() {
+        let mut cache = LRUCache::with_value_normalizer(2, |value: String| value.to_uppercase());
+        cache.put("A", String::from("hello"));
+        cache.put_with_ttl("B", String::from("World"), Duration::from_secs(60));
+
+        assert_eq!(cache.get(&"A"), Some(&String::from("HELLO")));
+        assert_eq!(cache.peek(&"B"), Some(&String::from("WORLD")));
+        assert_eq!(cache.put("A", String::from("again")), Some(String::from("HELLO")));
+        assert_eq!(cache.get(&"A"), Some(&String::from("AGAIN")));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut cache = LRUCache::with_capacity(4);
+        assert_eq!(cache.iter().next(), None);
+
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3), ("D", 4)] {
+            cache.put(key, value);
+        }
+        cache.get(&"B");
+        cache.get(&"A");
+        cache.invalidate(&"C");
+
+        let mut iter = cache.iter();
+        assert_eq!(iter.len(), 3);
+        assert_eq!(iter.next(), Some((&"A", &1)));
+        assert_eq!(iter.len(), 2);
+        assert_eq!(iter.collect::<Vec<_>>(), vec![(&"B", &2), (&"D", &4)]);
+        assert_eq!(cache.reachable_keys_forward(), vec!["A", "B", "D"]);
+    }
+
+    #[test]
+    fn test_distinct_value_count() {
+        let mut cache = LRUCache::with_capacity(5);
+        assert_eq!(cache.distinct_value_count(), 0);
+
+        for (key, value) in [(1, "red"), (2, "blue"), (3, "red"), (4, "green"), (5, "blue")] {
+            cache.put(key, value);
+        }
+        assert_eq!(cache.distinct_value_count(), 3);
+
+        cache.invalidate(&4);
+        assert_eq!(cache.distinct_value_count(), 2);
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let mut cache = LRUCache::with_capacity(4);
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3), ("D", 4), ("E", 5)] {
+            cache.put(key, String::from(key).repeat(value));
+        }
+        cache.get(&"C");
+        cache.invalidate(&"D");
+
+        let entries: Vec<(&str, String)> = cache.into_iter().collect();
+        assert_eq!(entries, vec![
+            ("B", String::from("BB")),
+            ("E", String::from("EEEEE")),
+            ("C", String::from("CCC")),
+        ]);
+    }
+
+    #[test]
+    fn test_keys_and_values() {
+        let mut cache = LRUCache::with_capacity(3);
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3), ("D", 4)] {
+            cache.put(key, value);
+        }
+        cache.get(&"B");
+        cache.invalidate(&"C");
+
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec![&"B", &"D"]);
+        assert_eq!(cache.values().collect::<Vec<_>>(), vec![&2, &4]);
+        assert_eq!(cache.keys().count(), cache.len());
+        assert_eq!(cache.values().count(), cache.len());
+        assert!(cache.is_mru(&"B"));
+    }
+
+    #[cfg(feature = "selftest")]
+    #[test]
+    fn test_self_benchmark() {
+        let result = LRUCache::self_benchmark(5000);
+        assert_eq!(result.ops, 5000);
+        assert!(result.elapsed > Duration::ZERO);
+        assert!(result.ops_per_sec > 0.0);
+        // The most popular keys fit in the cache: a Zipfian workload mostly hits.
+        assert!(result.hit_ratio > 0.3 && result.hit_ratio < 1.0);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut cache = LRUCache::with_capacity(6);
+        for key in 1..=6 {
+            cache.put(key, key * 10);
+        }
+        cache.get(&3);
+        assert_eq!(cache.reachable_keys_forward(), vec![3, 6, 5, 4, 2, 1]);
+
+        cache.retain(|key, _| key % 2 == 0);
+        assert_eq!(cache.reachable_keys_forward(), vec![6, 4, 2]);
+        assert_eq!(cache.len(), 3);
+        assert_links_consistent(&cache);
+
+        cache.retain(|_, value| *value == 40);
+        assert_eq!(cache.reachable_keys_forward(), vec![4]);
+        assert!(cache.is_mru(&4) && cache.is_lru(&4));
+
+        cache.put(7, 70);
+        assert_eq!(cache.reachable_keys_forward(), vec![7, 4]);
+        assert_links_consistent(&cache);
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut cache = LRUCache::with_capacity(4);
+        for key in 1..=4 {
+            cache.put(key, key * 10);
+        }
+        cache.get(&1);
+
+        assert_eq!(cache.drain().collect::<Vec<_>>(), vec![(2, 20), (3, 30), (4, 40), (1, 10)]);
+        assert!(cache.is_empty());
+
+        for key in 1..=4 {
+            cache.put(key, key * 10);
+        }
+        let mut drain = cache.drain();
+        assert_eq!(drain.next(), Some((1, 10)));
+        drop(drain);
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.capacity(), 4);
+
+        for key in 5..=9 {
+            cache.put(key, key * 10);
+        }
+        assert_eq!(cache.reachable_keys_forward(), vec![9, 8, 7, 6]);
+        assert_links_consistent(&cache);
+    }
+
+    #[test]
+    fn test_with_hasher() {
+        let mut cache = LRUCache::with_hasher(2, FnvBuildHasher::default());
+        cache.put("A", 1);
+        cache.put("B", 2);
+        assert_eq!(cache.get(&"A"), Some(&1));
+
+        cache.put("C", 3);
+        assert_eq!(cache.get(&"B"), None);
+        assert_eq!(cache.get(&"C"), Some(&3));
+        assert_eq!(cache.capacity(), 2);
+
+        let other = LRUCache::<&str, i32, _>::with_hasher(2, FnvBuildHasher::default());
+        assert_eq!(other.map.hasher().hash_one("A"), cache.map.hasher().hash_one("A"));
+    }
+
+    #[test]
+    fn test_borrowed_keys() {
+        let mut cache: LRUCache<String, i32> = LRUCache::with_capacity(3);
+        cache.put(String::from("A"), 1);
+        cache.put(String::from("B"), 2);
+        cache.put(String::from("C"), 3);
+
+        assert_eq!(cache.get("A"), Some(&1));
+        assert_eq!(cache.peek("B"), Some(&2));
+        assert!(cache.contains_key("C"));
+        assert!(!cache.contains_key("D"));
+
+        *cache.get_mut("C").unwrap() += 10;
+        assert_eq!(cache.pop("C"), Some(13));
+        cache.invalidate("B");
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get("B"), None);
+    }
+
+    #[test]
+    fn test_borrowed_key_loader() {
+        let mut cache: LRUCache<String, usize> = LRUCache::with_loader(2, |key: &String| Some(key.len()));
+        assert_eq!(cache.get("hello"), Some(&5));
+        assert!(cache.contains_key("hello"));
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put_with_ttl("short", 1, Duration::from_millis(10));
+        cache.put_with_ttl("long", 2, Duration::from_secs(60));
+        cache.put("forever", 3);
+        assert_eq!(cache.peek(&"short"), Some(&1));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.peek(&"short"), None);
+        assert!(!cache.contains_key(&"short"));
+        assert_eq!(cache.len(), 3);
+
+        assert_eq!(cache.get(&"short"), None);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"long"), Some(&2));
+        assert_eq!(cache.get(&"forever"), Some(&3));
+
+        // The slot of the expired entry is reused without evicting.
+        let slots = cache.entries.len();
+        cache.put("new", 4);
+        assert_eq!(cache.entries.len(), slots);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.stats_snapshot().evictions, 0);
+
+        // Adding the key again without a time-to-live removes its expiry.
+        cache.put_with_ttl("long", 5, Duration::from_millis(1));
+        cache.put("long", 6);
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.get(&"long"), Some(&6));
+    }
+
+    /// Clock that only moves forward when advanced by the test.
+    struct MockClock {
+        now: std::sync::Mutex<Instant>,
+    }
+
+    impl MockClock {
+        fn new() -> Arc<Self> {
+            Arc::new(MockClock { now: std::sync::Mutex::new(Instant::now()) })
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_ttl_with_mock_clock() {
+        let clock = MockClock::new();
+        let mut cache = LRUCache::with_capacity(3).with_clock(clock.clone());
+        cache.put_with_ttl("A", 1, Duration::from_secs(30));
+        cache.put_with_ttl("B", 2, Duration::from_secs(90));
+        cache.put("C", 3);
+
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(cache.get(&"A"), Some(&1));
+        let (_, idle) = cache.get_with_idle(&"B").unwrap();
+        assert_eq!(idle, Duration::from_secs(29));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.peek_state(&"A"), EntryState::Expired);
+        assert_eq!(cache.get(&"A"), None);
+        assert_eq!(cache.get(&"B"), Some(&2));
+
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(cache.get(&"B"), None);
+        assert_eq!(cache.get(&"C"), Some(&3));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_on_evict() {
+        let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let listener = evicted.clone();
+        let mut cache = LRUCache::with_capacity(2).on_evict(move |key, value| {
+            listener.lock().unwrap().push((key, value));
+        });
+
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3)] {
+            cache.put(key, value);
+        }
+        cache.get(&"B");
+        cache.invalidate(&"C");
+        cache.put("D", 4);
+        cache.put("E", 5);
+        assert_eq!(cache.push("F", 6), vec![]);
+
+        assert_eq!(*evicted.lock().unwrap(), vec![("A", 1), ("B", 2), ("D", 4)]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("A", 3);
+        cache.get(&"A");
+        cache.get(&"A");
+        cache.get(&"C");
+        cache.peek(&"B");
+        cache.peek(&"D");
+        cache.put("C", 4);
+        cache.put("D", 5);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.inserts), (2, 1, 2, 5));
+
+        cache.reset_stats();
+        assert_eq!(cache.stats(), CacheStats { len: 2, capacity: 2, ..CacheStats::default() });
+        assert_eq!(cache.eviction_rate(), 0.0);
+
+        cache.get(&"D");
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.get(&"C"), Some(&4));
+    }
+
+    thread_local! {
+        static CLONE_CALLS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Key counting how many times it gets cloned.
+    #[derive(PartialEq, Eq, Hash)]
+    struct CloneCountingKey(u32);
+
+    impl Clone for CloneCountingKey {
+        fn clone(&self) -> Self {
+            CLONE_CALLS.with(|calls| calls.set(calls.get() + 1));
+            CloneCountingKey(self.0)
+        }
+    }
+
+    #[test]
+    fn test_get_miss_without_loader_does_not_own_key() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put(CloneCountingKey(1), 1);
+
+        CLONE_CALLS.with(|calls| calls.set(0));
+        for key in 2..10 {
+            assert_eq!(cache.get(&CloneCountingKey(key)), None);
+        }
+        assert_eq!(CLONE_CALLS.with(Cell::get), 0);
+
+        let mut cache = LRUCache::with_loader(2, |key: &CloneCountingKey| Some(key.0));
+        CLONE_CALLS.with(|calls| calls.set(0));
+        assert_eq!(cache.get(&CloneCountingKey(3)), Some(&3));
+        assert!(CLONE_CALLS.with(Cell::get) > 0);
+    }
+
+    #[test]
+    fn test_partition_and_clone_top_keep_expirations() {
+        let clock = MockClock::new();
+        let mut cache = LRUCache::with_capacity(4).with_clock(clock.clone());
+        cache.put_with_ttl(1, 1, Duration::from_secs(10));
+        cache.put_with_ttl(2, 2, Duration::from_secs(30));
+        cache.put(3, 3);
+        cache.put_with_ttl(4, 4, Duration::from_secs(30));
+        clock.advance(Duration::from_secs(10));
+
+        let top = cache.clone_top(4);
+        assert_eq!(top.iter().map(|(key, _)| *key).collect::<Vec<_>>(), vec![4, 3, 2]);
+
+        let (mut even, mut odd) = cache.partition(|key, _| key % 2 == 0);
+        assert_eq!(even.len(), 2);
+        assert_eq!(odd.len(), 1);
+        assert_eq!(odd.get(&1), None);
+
+        let mut top = top;
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(even.get(&2), None);
+        assert_eq!(even.get(&4), None);
+        assert_eq!(odd.get(&3), Some(&3));
+        assert_eq!(top.get(&2), None);
+        assert_eq!(top.get(&3), Some(&3));
+    }
+
+    #[test]
+    fn test_get_or_load_from_reloads_expired_entry() {
+        let clock = MockClock::new();
+        let mut cache = LRUCache::with_capacity(2).with_clock(clock.clone());
+        let mut backing = HashMap::from([("A", 2)]);
+        cache.put_with_ttl("A", 1, Duration::from_secs(10));
+
+        assert_eq!(cache.get_or_load_from(&"A", &mut backing), Some(&1));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get_or_load_from(&"A", &mut backing), Some(&2));
+    }
+
+    #[test]
+    fn test_get_entry_and_increment_ignore_expired_entry() {
+        let clock = MockClock::new();
+        let mut cache = LRUCache::with_capacity(2).with_clock(clock.clone());
+        cache.put_with_ttl("A", 5, Duration::from_secs(10));
+        cache.put_with_ttl("B", 5, Duration::from_secs(10));
+        clock.advance(Duration::from_secs(10));
+
+        assert!(cache.get_entry(&"A").is_none());
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.increment("B", 1), 1);
+        assert_eq!(cache.get(&"B"), Some(&1));
+    }
+
+    #[test]
+    fn test_transfer_to_keeps_metadata() {
+        let clock = MockClock::new();
+        let mut source = LRUCache::with_capacity(2).with_clock(clock.clone());
+        let mut dest = LRUCache::with_capacity(1).with_clock(clock.clone());
+        source.put_with_ttl("A", 1, Duration::from_secs(10));
+        source.put_tagged("B", 2, 7);
+        source.pin(&"B");
+
+        assert!(source.transfer_to(&"B", &mut dest));
+        assert_eq!(dest.get(&"B"), Some(&2));
+        assert_eq!(dest.tag_of(&"B"), Some(7));
+        assert!(dest.is_pinned(&"B"));
+
+        let mut dest = LRUCache::with_capacity(1).with_clock(clock.clone());
+        assert!(source.transfer_to(&"A", &mut dest));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(dest.get(&"A"), None);
+    }
+
+    #[test]
+    fn test_put_if_better_replaces_expired_value() {
+        let clock = MockClock::new();
+        let mut cache = LRUCache::with_capacity(2).with_clock(clock.clone());
+        cache.put_with_ttl("A", 10, Duration::from_secs(10));
+
+        assert!(!cache.put_if_better("A", 5, |new, old| new > old));
+        clock.advance(Duration::from_secs(10));
+        assert!(cache.put_if_better("A", 5, |new, old| new > old));
+        assert_eq!(cache.get(&"A"), Some(&5));
+    }
+
+    #[test]
+    fn test_put_mut_rejected_insert() {
+        let mut cache = LRUCache::with_capacity(0);
+        assert_eq!(cache.put_mut("A", 1), None);
+        assert_eq!(cache.get_or_insert_with("A", || 1), None);
+
+        let mut cache = LRUCache::with_capacity(1);
+        cache.put("A", 1);
+        cache.pin(&"A");
+        assert_eq!(cache.get_or_insert_with("B", || 2), None);
+        assert_eq!(cache.get_or_try_insert_with::<_, ()>("B", || Ok(2)), Ok(None));
+        assert_eq!(cache.get_or_insert_with("A", || 10), Some(&1));
+    }
+
+    #[test]
+    fn test_iter_len_skips_reserved_slots() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        let token = cache.reserve_slot("B");
+        cache.put("C", 3);
+
+        let mut iter = cache.iter();
+        assert_eq!(iter.len(), 2);
+        iter.next();
+        assert_eq!(iter.len(), 1);
+        assert_eq!(cache.keys().count(), cache.iter().len());
+
+        cache.fill_slot(token, 2);
+        assert_eq!(cache.iter().len(), 3);
+    }
+
+    #[test]
+    fn test_inserts_count_only_stored_entries() {
+        let mut cache = LRUCache::with_capacity(1);
+        cache.put("A", 1);
+        cache.pin(&"A");
+        cache.put("B", 2);
+        assert_eq!(cache.get(&"B"), None);
+        assert_eq!(cache.stats().inserts, 1);
+
+        cache.unpin(&"A");
+        let token = cache.reserve_slot("C");
+        assert_eq!(cache.stats().inserts, 1);
+        cache.fill_slot(token, 3);
+        assert_eq!(cache.stats().inserts, 2);
+    }
+
+    #[cfg(feature = "oplog")]
+    #[test]
+    fn test_op_log_covers_every_path() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.push("A", 1);
+        let token = cache.reserve_slot("B");
+        cache.fill_slot(token, 2);
+        cache.record_hit(&"A");
+        cache.get_or_insert_with("C", || 3);
+        cache.get_entry(&"A").unwrap().remove();
+        cache.put("D", 4);
+        cache.clear();
+
+        assert_eq!(
+            cache.op_log(),
+            &[
+                Op::Put("A"), Op::Put("B"), Op::Get("A"), Op::Put("C"), Op::Evict("B"),
+                Op::Invalidate("A"), Op::Put("D"), Op::Invalidate("C"), Op::Invalidate("D"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peek_lru_skips_pinned_tail() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+        cache.pin(&"A");
+
+        assert_eq!(cache.peek_lru(), Some((&"B", &2)));
+        cache.put("D", 4);
+        assert_eq!(cache.get(&"B"), None);
+
+        cache.pin(&"C");
+        cache.pin(&"D");
+        assert_eq!(cache.peek_lru(), None);
+    }
+
+    #[test]
+    fn test_entry_promote_respects_lazy_promotion_and_threshold() {
+        let mut cache = LRUCache::with_lazy_promotion(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+
+        cache.get_entry(&"A").unwrap().promote();
+        assert_eq!(cache.reachable_keys_forward(), vec!["B", "A"]);
+        cache.flush_promotions();
+        assert_eq!(cache.reachable_keys_forward(), vec!["A", "B"]);
+
+        let mut cache = LRUCache::with_promotion_threshold(3, 2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.get_entry(&"B").unwrap().promote();
+        assert_eq!(cache.reachable_keys_forward(), vec!["A", "B"]);
+        cache.get_entry(&"B").unwrap().promote();
+        assert_eq!(cache.reachable_keys_forward(), vec!["B", "A"]);
+    }
+
+    #[test]
+    fn test_reserve_slot_refused_for_existing_value() {
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+
+        let token = cache.reserve_slot("A");
+        assert_eq!(cache.get(&"A"), Some(&1));
+        assert!(!cache.fill_slot(token, 10));
+        assert_eq!(cache.get(&"A"), Some(&1));
+    }
+
+    #[test]
+    fn test_fill_slot_normalizes_value() {
+        let mut cache = LRUCache::with_value_normalizer(2, |value: i32| value.clamp(0, 10));
+        let token = cache.reserve_slot("A");
+
+        assert!(cache.fill_slot(token, 42));
+        assert_eq!(cache.get(&"A"), Some(&10));
+    }
+
+    #[test]
+    fn test_with_clock_combines_with_constructors() {
+        let clock = MockClock::new();
+        let mut cache = LRUCache::with_hasher(2, FnvBuildHasher::default()).with_clock(clock.clone());
+        cache.put_with_ttl("A", 1, Duration::from_secs(10));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get(&"A"), None);
+
+        let mut cache = LRUCache::with_lazy_promotion(2).with_clock(clock.clone());
+        cache.put_with_ttl("A", 1, Duration::from_secs(10));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get(&"A"), None);
+    }
+
+    #[test]
+    fn test_transfer_to_refused_by_full_dest() {
+        let mut source = LRUCache::with_capacity(2);
+        let mut dest = LRUCache::with_capacity(1);
+        source.put("A", 1);
+        dest.put("B", 2);
+        dest.pin(&"B");
+
+        assert!(!source.transfer_to(&"A", &mut dest));
+        assert_eq!(source.get(&"A"), Some(&1));
+        assert_eq!(dest.get(&"A"), None);
+
+        let mut empty = LRUCache::with_capacity(0);
+        assert!(!source.transfer_to(&"A", &mut empty));
+        assert_eq!(source.get(&"A"), Some(&1));
+    }
+
+    #[test]
+    fn test_put_with_max_ttl_never_expires() {
+        let clock = MockClock::new();
+        let mut cache = LRUCache::with_capacity(2).with_clock(clock.clone());
+        cache.put_with_ttl("A", 1, Duration::MAX);
+
+        clock.advance(Duration::from_secs(3600 * 24 * 365));
+        assert_eq!(cache.get(&"A"), Some(&1));
+    }
+
+    #[test]
+    fn test_expired_entries_skipped_by_iterators() {
+        let clock = MockClock::new();
+        let mut cache = LRUCache::with_capacity(3).with_clock(clock.clone());
+        cache.put("A", 1);
+        cache.put_with_ttl("B", 2, Duration::from_secs(10));
+        cache.put("C", 3);
+        clock.advance(Duration::from_secs(10));
+
+        assert_eq!(cache.iter().len(), 2);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec!["C", "A"]);
+        assert_eq!(cache.values().sum::<i32>(), 4);
+        assert_eq!(cache.fold(0, |count, _, _| count + 1), 2);
+        assert_eq!(format!("{:?}", cache), r#"LRUCache { capacity: 3, entries: {"C": 3, "A": 1} }"#);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.clone().into_iter().collect::<Vec<_>>(), vec![("A", 1), ("C", 3)]);
+    }
+
+    #[test]
+    fn test_put_over_expired_entry_resets_it() {
+        let clock = MockClock::new();
+        let mut cache = LRUCache::with_capacity(2).with_clock(clock.clone());
+        cache.put_with_ttl("A", 1, Duration::from_secs(10));
+        cache.pin(&"A");
+        cache.put("B", 2);
+        clock.advance(Duration::from_secs(10));
+
+        assert_eq!(cache.put("A", 3), None);
+        assert!(!cache.is_pinned(&"A"));
+        assert_eq!(cache.iter_insertion_order(), vec![(&"B", &2), (&"A", &3)]);
+    }
+
+    #[test]
+    fn test_fill_slot_checks_generation() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.invalidate(&"A");
+        let token = cache.reserve_slot("C");
+        cache.compact();
+        assert!(cache.fill_slot(token, 3));
+        assert_eq!(cache.get(&"C"), Some(&3));
+
+        let stale = cache.reserve_slot("D");
+        cache.invalidate(&"D");
+        let token = cache.reserve_slot("D");
+        assert!(!cache.fill_slot(stale, 0));
+        assert!(cache.fill_slot(token, 4));
+        assert_eq!(cache.get(&"D"), Some(&4));
+    }
+
+    #[test]
+    fn test_invariants_hold_during_shrink() {
+        let mut cache = LRUCache::with_capacity(3);
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3)] {
+            cache.put(key, value);
+        }
+        cache.set_capacity_lazy(1);
+        cache.check_invariants();
+        cache.put("D", 4);
+        cache.check_invariants();
+        cache.put("E", 5);
+        cache.check_invariants();
+        assert_eq!(cache.len(), 1);
+
+        let mut cache = LRUCache::with_capacity(2);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.pin(&"A");
+        cache.pin(&"B");
+        cache.resize(1);
+        cache.check_invariants();
+        cache.unpin(&"A");
+        cache.put("C", 3);
+        cache.check_invariants();
+    }
+
+    #[test]
+    fn test_cache_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<LRUCache<u32, u32>>();
+        assert_send_sync::<LRUCache<String, Vec<u8>, FnvBuildHasher>>();
+    }
+
+    #[test]
+    fn test_peek_lru_with_lazy_promotion() {
+        let mut cache = LRUCache::with_lazy_promotion(3);
+        for (key, value) in [("A", 1), ("B", 2), ("C", 3)] {
+            cache.put(key, value);
+        }
+        cache.get(&"A");
+        cache.get(&"B");
+
+        assert_eq!(cache.peek_lru(), Some((&"C", &3)));
+        cache.pin(&"C");
+        assert_eq!(cache.peek_lru(), Some((&"A", &1)));
+        cache.put("D", 4);
+        assert_eq!(cache.get(&"A"), None);
+    }
+
+    #[test]
+    fn test_eviction_candidates_with_lazy_promotion() {
+        let mut cache = LRUCache::with_lazy_promotion(4);
+        for key in ["A", "B", "C", "D"] {
+            cache.put(key, ());
+        }
+        cache.get(&"A");
+        cache.get(&"C");
+
+        let keys: Vec<_> = cache.eviction_candidates(4).into_iter().map(|(key, _, _)| *key).collect();
+        assert_eq!(keys, vec!["B", "D", "A", "C"]);
+
+        for (expected, key) in keys.into_iter().zip(["E", "F", "G", "H"]) {
+            cache.put(key, ());
+            assert!(!cache.contains_key(&expected));
+        }
+    }
+
+    #[test]
+    fn test_try_put_gives_back_rejected_entry() {
+        let mut cache = LRUCache::with_capacity(1);
+        assert_eq!(cache.try_put("A", 1), Ok(None));
+        assert_eq!(cache.try_put("A", 2), Ok(Some(1)));
+        cache.pin(&"A");
+
+        assert_eq!(cache.try_put("B", 3), Err(("B", 3)));
+        assert_eq!(cache.try_put("A", 4), Ok(Some(2)));
+        assert_eq!(cache.stats().inserts, 3);
+
+        let mut empty = LRUCache::with_capacity(0);
+        assert_eq!(empty.try_put("A", 1), Err(("A", 1)));
+    }
+
+    #[cfg(feature = "oplog")]
+    #[test]
+    fn test_op_log_skips_rejected_put() {
+        let mut cache = LRUCache::with_capacity(1);
+        cache.put("A", 1);
+        cache.pin(&"A");
+        cache.put("B", 2);
+
+        assert_eq!(cache.op_log(), &[Op::Put("A")]);
+    }
+
+    #[test]
+    fn test_rebuild_map_stops_on_cycle() {
+        let mut cache = LRUCache::with_capacity(3);
+        cache.put("A", 1);
+        cache.put("B", 2);
+        cache.put("C", 3);
+
+        let last = cache.last.unwrap();
+        cache.entries[last].next = cache.first;
+        assert!(!cache.rebuild_map());
+        assert_eq!(cache.map.len(), 3);
+    }
+}
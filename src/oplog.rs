@@ -0,0 +1,24 @@
+/// # Operation log
+///
+/// Operation performed on a cache, recorded with its key when the `oplog` feature is enabled.
+/// Replaying a recorded log helps reproducing bugs seen on real workloads.
+///
+/// **Exemple**:
+/// ```
+///use lru_cache::cache::Cache;
+///use lru_cache::lru_cache::LRUCache;
+///use lru_cache::oplog::Op;
+///
+/// let mut cache = LRUCache::with_capacity(1);
+/// cache.put(1, "A");
+/// cache.put(2, "B");
+///
+/// assert_eq!(cache.op_log(), &[Op::Put(1), Op::Put(2), Op::Evict(1)]);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op<K> {
+    Put(K),
+    Get(K),
+    Invalidate(K),
+    Evict(K),
+}
@@ -0,0 +1,40 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+/// # Xorshift generator
+///
+/// Small pseudo-random generator for the probabilistic parts of the cache.
+/// It is not cryptographically secure, but the same seed always gives the same sequence,
+/// which makes behaviors depending on it reproducible in tests.
+#[derive(Clone, Debug)]
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    /// Create a generator from a seed. The seed is mixed first (splitmix64), so that small seeds
+    /// do not produce small first numbers, and zero, which would only produce zeros, is replaced.
+    pub(crate) fn new(seed: u64) -> Self {
+        let mut state = seed.wrapping_add(0x9e3779b97f4a7c15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94d049bb133111eb);
+        state ^= state >> 31;
+
+        XorShift(if state == 0 { 0x2545f4914f6cdd1d } else { state })
+    }
+
+    /// Create a generator seeded from the random keys of the standard library hasher.
+    pub(crate) fn from_entropy() -> Self {
+        Self::new(RandomState::new().hash_one(0u64))
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Random number in `(0, 1]`.
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,182 @@
+#[cfg(feature = "instrument")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "instrument")]
+use std::time::Duration;
+
+/// # Cache statistics
+///
+/// Snapshot of the counters of a cache, along with its size at the time of the snapshot.
+/// Hits and misses count the lookups of `get` and its variants, `peek` and `contains_key`
+/// are not counted. Inserts count the keys added or updated.
+///
+/// **Exemple**:
+/// ```
+///use lru_cache::cache::Cache;
+///use lru_cache::lru_cache::LRUCache;
+///
+/// let mut cache = LRUCache::with_capacity(2);
+/// cache.put(1, "A");
+/// cache.get(&1);
+/// cache.get(&2);
+///
+/// let stats = cache.stats();
+/// assert_eq!(stats.hits, 1);
+/// assert_eq!(stats.misses, 1);
+/// assert_eq!(stats.inserts, 1);
+/// assert_eq!(stats.len, 1);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+    pub inserts: u64,
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    /// Number of lookups, hits and misses.
+    pub fn accesses(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// Ratio of lookups that were hits, or 0 when there was no lookup.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.accesses() {
+            0 => 0.0,
+            accesses => self.hits as f64 / accesses as f64,
+        }
+    }
+
+    /// Serialize the statistics as a JSON object.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"hits\":{},\"misses\":{},\"evictions\":{},\"invalidations\":{},\"inserts\":{},\"len\":{},\"capacity\":{}}}",
+            self.hits, self.misses, self.evictions, self.invalidations, self.inserts, self.len, self.capacity
+        )
+    }
+}
+
+/// Result of `LRUCache::self_benchmark`, with the `selftest` feature.
+#[cfg(feature = "selftest")]
+#[derive(Clone, Debug)]
+pub struct BenchResult {
+    pub ops: usize,
+    pub elapsed: std::time::Duration,
+    pub ops_per_sec: f64,
+    pub hit_ratio: f64,
+}
+
+/// Number of operations after which the eviction window is rolled over.
+const EVICTION_WINDOW: u64 = 1000;
+
+/// Counters updated by the cache operations.
+///
+/// **window_operations** and **window_evictions** count the lookups, inserts and evictions
+/// of the current window, **previous_window** the ones of the window before it.
+/// **weighted_hits** and **weighted_misses** add up the weight of the entries looked up by `get`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) evictions: u64,
+    pub(crate) invalidations: u64,
+    pub(crate) inserts: u64,
+    pub(crate) consecutive_misses: u64,
+    pub(crate) window_operations: u64,
+    pub(crate) window_evictions: u64,
+    pub(crate) previous_window: (u64, u64),
+    pub(crate) weighted_hits: u64,
+    pub(crate) weighted_misses: u64,
+    #[cfg(feature = "instrument")]
+    pub(crate) lookups: LookupCounter,
+    #[cfg(feature = "instrument")]
+    pub(crate) lookup_nanos: LookupCounter,
+}
+
+/// Counter incremented by lookups through a shared reference, with the `instrument` feature.
+/// It is atomic so that the cache stays `Sync` with the feature enabled.
+#[cfg(feature = "instrument")]
+#[derive(Debug, Default)]
+pub(crate) struct LookupCounter(AtomicU64);
+
+#[cfg(feature = "instrument")]
+impl LookupCounter {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "instrument")]
+impl Clone for LookupCounter {
+    fn clone(&self) -> Self {
+        LookupCounter(AtomicU64::new(self.get()))
+    }
+}
+
+impl Counters {
+    /// Record a lookup that found its key.
+    pub(crate) fn record_hit(&mut self) {
+        self.hits += 1;
+        self.consecutive_misses = 0;
+        self.record_operation();
+    }
+
+    /// Record a lookup that did not find its key.
+    pub(crate) fn record_miss(&mut self) {
+        self.misses += 1;
+        self.consecutive_misses += 1;
+        self.record_operation();
+    }
+
+    /// Record a key added or updated.
+    pub(crate) fn record_insert(&mut self) {
+        self.inserts += 1;
+        self.record_operation();
+    }
+
+    /// Record an entry evicted to make room for another.
+    pub(crate) fn record_eviction(&mut self) {
+        self.evictions += 1;
+        self.window_evictions += 1;
+    }
+
+    /// Ratio of the weight of the lookups that were hits, or 0 when there was no lookup.
+    pub(crate) fn weighted_hit_ratio(&self) -> f64 {
+        match self.weighted_hits + self.weighted_misses {
+            0 => 0.0,
+            weight => self.weighted_hits as f64 / weight as f64,
+        }
+    }
+
+    /// Evictions per operation over the last `EVICTION_WINDOW` to `2 * EVICTION_WINDOW` operations.
+    pub(crate) fn eviction_rate(&self) -> f64 {
+        let (previous_operations, previous_evictions) = self.previous_window;
+        match previous_operations + self.window_operations {
+            0 => 0.0,
+            operations => (previous_evictions + self.window_evictions) as f64 / operations as f64,
+        }
+    }
+
+    fn record_operation(&mut self) {
+        self.window_operations += 1;
+        if self.window_operations >= EVICTION_WINDOW {
+            self.previous_window = (self.window_operations, self.window_evictions);
+            self.window_operations = 0;
+            self.window_evictions = 0;
+        }
+    }
+
+    /// Record the duration of a key lookup.
+    #[cfg(feature = "instrument")]
+    pub(crate) fn record_lookup(&self, elapsed: Duration) {
+        self.lookups.add(1);
+        self.lookup_nanos.add(elapsed.as_nanos() as u64);
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Mutex, MutexGuard};
+use crate::cache::Cache;
+use crate::lru_cache::LRUCache;
+
+/// # Sync Cache
+///
+/// LRU cache that can be shared between threads, every operation locking the whole cache.
+///
+/// **inner** is the cache, behind a mutex.
+///
+/// **Exemple**:
+/// ```
+///use lru_cache::sync_cache::SyncCache;
+///
+/// let cache = SyncCache::with_capacity(2);
+/// cache.put(1, "A");
+///
+/// assert_eq!(cache.get(&1), Some("A"));
+/// ```
+pub struct SyncCache<K, V, S = RandomState> {
+    inner: Mutex<LRUCache<K, V, S>>,
+}
+
+impl<K, V> SyncCache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Create a new cache with a maximum size, using the default hasher.
+    pub fn with_capacity(max_size: usize) -> Self {
+        Self::from_cache(LRUCache::with_capacity(max_size))
+    }
+}
+
+impl<K, V, S> SyncCache<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Default,
+{
+    /// Share an existing cache between threads.
+    pub fn from_cache(cache: LRUCache<K, V, S>) -> Self {
+        SyncCache { inner: Mutex::new(cache) }
+    }
+
+    /// Get a copy of the value associated with the given key, moving it to the front.
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self._lock().get(key).cloned()
+    }
+
+    /// Add the given key-value to the cache, returning the previous value of the key if any.
+    pub fn put(&self, key: K, value: V) -> Option<V> {
+        self._lock().put(key, value)
+    }
+
+    /// Remove the given key from the cache.
+    pub fn invalidate(&self, key: &K) {
+        self._lock().invalidate(key)
+    }
+
+    /// Run `f` on the cache with the lock held for the whole call, so that several
+    /// operations, such as a check followed by an insertion, are performed atomically.
+    /// Other threads block on the cache until `f` returns.
+    pub fn compute<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut LRUCache<K, V, S>) -> R,
+    {
+        f(&mut self._lock())
+    }
+
+    /// Lock the cache, recovering it if another thread panicked while holding the lock.
+    fn _lock(&self) -> MutexGuard<'_, LRUCache<K, V, S>> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_compute_is_atomic() {
+        let cache = SyncCache::with_capacity(4);
+
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        cache.compute(|cache| {
+                            let count = cache.get(&"count").copied().unwrap_or(0);
+                            cache.put("count", count + 1);
+                        });
+                    }
+                });
+            }
+        });
+
+        assert_eq!(cache.get(&"count"), Some(8000));
+        assert_eq!(cache.compute(|cache| cache.len()), 1);
+    }
+}